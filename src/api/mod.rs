@@ -108,9 +108,29 @@ impl Server {
                                     return;
                                 }
                             };
-                            miner.start(lambda);
+                            if !miner.start(lambda) {
+                                respond_result!(req, false, "miner has shut down");
+                                return;
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/pause" => {
+                            if !miner.pause() {
+                                respond_result!(req, false, "miner has shut down");
+                                return;
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stop" => {
+                            if !miner.exit() {
+                                respond_result!(req, false, "miner has shut down");
+                                return;
+                            }
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -129,13 +149,26 @@ impl Server {
                                 }
                             };
                             // have to implement
-                            txgen.start(theta);
+                            if !txgen.start(theta) {
+                                respond_result!(req, false, "transaction generator has shut down");
+                                return;
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/stop" => {
+                            if !txgen.exit() {
+                                respond_result!(req, false, "transaction generator has shut down");
+                                return;
+                            }
                             respond_result!(req, true, "ok");
                         }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            network.broadcast(Message::Ping(rand::random()));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/round-trips" => {
+                            respond_json!(req, network.round_trips());
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
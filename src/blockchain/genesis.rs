@@ -0,0 +1,102 @@
+use crate::types::address::Address;
+use crate::types::hash::{H256, Hashable};
+use super::params::ConsensusParams;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::convert::TryInto;
+
+/// Specification of the genesis block and of the initial allocations of a chain.
+/// Every node started from the same spec and ledger model derives the same genesis hash.
+///
+/// The spec is a JSON file such as
+/// `{"timestamp": 0, "difficulty": "0a0a...0a", "allocations": [{"address": "1851...cba0", "value": 1000}]}`
+/// where the difficulty and the addresses are hex encoded. Every field is optional, and the
+/// consensus parameters are read from the top level of the spec.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct GenesisSpec {
+    #[serde(default)]
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub params: ConsensusParams,
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+}
+
+/// Initial value given to an address: an account balance in the account model,
+/// an unspent output of the genesis block in the UTXO model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Allocation {
+    #[serde(deserialize_with = "deserialize_address")]
+    pub address: Address,
+    pub value: u128,
+}
+
+impl Hashable for Allocation {
+    fn hash(&self) -> H256 {
+        ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(&self).unwrap()).into()
+    }
+}
+
+impl GenesisSpec {
+    /// Parse a genesis spec from JSON, rejecting consensus parameters the chain cannot run with
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let spec: Self = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        spec.params.validate()?;
+        Ok(spec)
+    }
+
+    /// Load a genesis spec from a JSON file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_json(&json)
+    }
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    hex::decode(&s).map_err(D::Error::custom)
+}
+
+pub(super) fn deserialize_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<H256, D::Error> {
+    let bytes: [u8; 32] = deserialize_hex(deserializer)?
+        .as_slice()
+        .try_into()
+        .map_err(|_| D::Error::custom("expected 32 hex encoded bytes"))?;
+    Ok(bytes.into())
+}
+
+fn deserialize_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+    let bytes: [u8; 20] = deserialize_hex(deserializer)?
+        .as_slice()
+        .try_into()
+        .map_err(|_| D::Error::custom("expected 20 hex encoded bytes"))?;
+    Ok(bytes.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        let spec = GenesisSpec::from_json(r#"{
+            "timestamp": 1600000000000,
+            "difficulty": "00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "allocations": [{"address": "1851a0eae0060a132cf0f64a0ffaea248de6cba0", "value": 1000}]
+        }"#).unwrap();
+        assert_eq!(spec.timestamp, 1600000000000);
+        assert_eq!(spec.params.initial_difficulty, hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into());
+        assert_eq!(spec.allocations[0].address, hex!("1851a0eae0060a132cf0f64a0ffaea248de6cba0").into());
+        assert_eq!(spec.allocations[0].value, 1000);
+
+        let default = GenesisSpec::from_json("{}").unwrap();
+        assert_eq!(default.params.initial_difficulty, ConsensusParams::default().initial_difficulty);
+        assert!(GenesisSpec::from_json(r#"{"difficulty": "0a"}"#).is_err());
+        assert!(GenesisSpec::from_json(r#"{"max_adjustment": 0}"#).is_err());
+        assert!(GenesisSpec::from_json(r#"{"median_time_span": 0}"#).is_err());
+        assert!(GenesisSpec::from_json(r#"{"target_block_time": 0}"#).is_err());
+        let short = r#"{"retarget_interval": 2, "target_block_time": 1, "max_adjustment": 4}"#;
+        assert!(GenesisSpec::from_json(short).is_err());
+        assert!(GenesisSpec::from_json(r#"{"retarget_interval": 0, "target_block_time": 1}"#).is_ok());
+    }
+}
@@ -1,49 +1,255 @@
+pub mod genesis;
+pub mod params;
+pub mod state;
+pub mod store;
+pub mod utxo;
+
 use crate::types::block::{Block, Header, Data};
 use crate::types::hash::H256;
 use crate::types::merkle::MerkleTree;
 use crate::types::hash::Hashable;
-use crate::types::transaction::SignedTransaction;
+use crate::types::transaction::{Payload, SignedTransaction};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use log::{error, info, warn};
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
+use self::genesis::GenesisSpec;
+use self::params::ConsensusParams;
+use self::state::{LedgerModel, State, TransactionError};
+use self::store::BlockStore;
+use crate::types::utxo::{Input, Output};
+
+/// Reasons for which a block is rejected by the blockchain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// the parent of the block is not in the chain
+    UnknownParent(H256),
+    /// a transaction of the block, identified by its hash, cannot be executed
+    InvalidTransaction(H256, TransactionError),
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            BlockError::InvalidTransaction(hash, e) => write!(f, "transaction {}: {}", hash, e),
+        }
+    }
+}
+
+/// Change of the tip caused by inserting a block. A plain extension of the longest chain is
+/// a reorg that disconnects no blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reorg {
+    /// blocks that left the longest chain, from the old tip down to the fork point
+    pub disconnected: Vec<H256>,
+    /// blocks that joined the longest chain, from the fork point up to the new tip
+    pub connected: Vec<H256>,
+}
 
 pub struct Blockchain {
     pub blocks: HashMap<H256, Block>,
     heights: HashMap<H256, u128>,
-    tip: H256
+    // total work of the chain ending at each block, keyed by block hash
+    chainwork: HashMap<H256, H256>,
+    // ledger state after executing each block, keyed by block hash
+    states: HashMap<H256, State>,
+    genesis: H256,
+    tip: H256,
+    model: LedgerModel,
+    params: ConsensusParams,
+    // on-disk copy of the blocks, if the chain was opened from a data directory
+    store: Option<BlockStore>
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new account-model blockchain, only containing the genesis block
     pub fn new() -> Self {
-        let genesis_timestamp = 0;
+        Self::with_model(LedgerModel::Account)
+    }
+
+    /// Create a new blockchain of the given ledger model, only containing the default genesis block
+    pub fn with_model(model: LedgerModel) -> Self {
+        Self::from_genesis(&GenesisSpec::default(), model)
+    }
+
+    /// Create a new blockchain of the given ledger model, only containing the genesis block
+    /// described by the spec
+    pub fn from_genesis(spec: &GenesisSpec, model: LedgerModel) -> Self {
         let data = Data{data: Vec::new()};
-        let merkle_root = MerkleTree::new(&data.data).root();
+        // the genesis header commits to the rules of the chain and to the initial allocations, so
+        // that nodes of another ledger model or other consensus parameters get another genesis hash
+        let rules = bincode::serialize(&(model, &spec.params)).unwrap();
+        let rules: H256 = ring::digest::digest(&ring::digest::SHA256, &rules).into();
+        let merkle_root = MerkleTree::new(&[rules, MerkleTree::new(&spec.allocations).root()]).root();
         let parent: H256 = [0u8; 32].into();
-        // when changing the difficulty make sure to change it in miner/mod.rs as well!!! 
-        let difficulty: H256 = [10u8; 32].into();
-        let genesis_header = Header{parent, nonce: 0, difficulty, timestamp:genesis_timestamp, merkle_root};
+        let genesis_header = Header{parent, nonce: 0, difficulty: spec.params.initial_difficulty, timestamp: spec.timestamp, merkle_root};
         let genesis = Block{header: genesis_header, data};
         let mut blocks: HashMap<H256, Block> = HashMap::new();
         let hash = genesis.hash();
         blocks.insert(hash, genesis);
         let mut heights: HashMap<H256, u128> = HashMap::new();
         heights.insert(hash, 0);        
-        Blockchain{blocks,  heights, tip: hash}
+        let mut chainwork: HashMap<H256, H256> = HashMap::new();
+        chainwork.insert(hash, spec.params.initial_difficulty.work());
+        let mut states: HashMap<H256, State> = HashMap::new();
+        states.insert(hash, Self::genesis_state(spec, model, hash));
+        Blockchain{blocks,  heights, chainwork, states, genesis: hash, tip: hash, model, params: spec.params.clone(), store: None}
+    }
+
+    /// Open the blockchain stored in a data directory, replaying the stored blocks on top of
+    /// the genesis block described by the spec. Blocks inserted later are appended to the store.
+    pub fn open(datadir: &Path, spec: &GenesisSpec, model: LedgerModel) -> io::Result<Self> {
+        let mut blockchain = Self::from_genesis(spec, model);
+        let (store, stored) = BlockStore::open(datadir)?;
+        for block in &stored {
+            if let Err(e) = blockchain.insert(block) {
+                warn!("Stored block {} skipped: {}", block.hash(), e);
+            }
+        }
+        info!("Loaded {} blocks from {}", stored.len(), datadir.display());
+        blockchain.store = Some(store);
+        Ok(blockchain)
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Build the state holding the initial allocations of a genesis spec
+    fn genesis_state(spec: &GenesisSpec, model: LedgerModel, genesis_hash: H256) -> State {
+        let mut state = State::new(model);
+        match &mut state {
+            State::Account(accounts) => {
+                for allocation in &spec.allocations {
+                    accounts.credit(&allocation.address, allocation.value);
+                }
+            }
+            State::Utxo(utxos) => {
+                // allocations are the outputs of the genesis block, in the order of the spec
+                for (index, allocation) in spec.allocations.iter().enumerate() {
+                    let input = Input { prev_tx: genesis_hash, index: index as u32 };
+                    utxos.add_output(input, Output { receiver: allocation.address, value: allocation.value });
+                }
+            }
+        }
+        state
+    }
+
+    /// Insert a block into blockchain, executing its transactions on top of its parent's state.
+    /// The block is rejected, and the chain left untouched, if any transaction fails to execute.
+    /// Returns the reorg if the block moved the tip.
+    pub fn insert(&mut self, block: &Block) -> Result<Option<Reorg>, BlockError> {
+        let parent = block.get_parent();
+        let state = self.execute(block)?;
         let new_block: Block = block.clone();
         let hash = block.hash();
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.append(block) {
+                error!("Error storing block {}: {}", hash, e);
+            }
+        }
         self.blocks.insert(hash, new_block);
-        let new_block_height = self.heights.get(&block.get_parent()).unwrap() + 1;
-        let longest_chain_height: u128 = self.heights.get(&self.tip).unwrap().clone();
+        let new_block_height = self.heights.get(&parent).unwrap() + 1;
         self.heights.insert(hash, new_block_height);
+        let new_block_work = self.chainwork[&parent].saturating_add(&block.get_difficulty().work());
+        self.chainwork.insert(hash, new_block_work);
+        self.states.insert(hash, state);
         
-        if new_block_height > longest_chain_height {
+        // the tip is the block with the most cumulative work, the first one seen on ties
+        if new_block_work > self.chainwork[&self.tip] {
+            let reorg = self.reorg(self.tip, hash);
             self.tip = hash;
+            return Ok(Some(reorg));
+        }
+        Ok(None)
+    }
+
+    /// Execute a block on top of its parent's state and return the resulting state.
+    /// An optional coinbase in the first position may claim the block reward and the fees of the
+    /// other transactions, and is credited after them.
+    pub fn execute(&self, block: &Block) -> Result<State, BlockError> {
+        let parent = block.get_parent();
+        let mut state = match self.states.get(&parent) {
+            Some(state) => state.clone(),
+            None => return Err(BlockError::UnknownParent(parent)),
+        };
+        let transactions = &block.data.data;
+        let coinbase = match transactions.first().map(|transaction| &transaction.transaction) {
+            Some(Payload::Coinbase(coinbase)) => Some(coinbase),
+            _ => None,
+        };
+        let mut fees: u128 = 0;
+        let skip = if coinbase.is_some() { 1 } else { 0 };
+        for transaction in &transactions[skip..] {
+            match state.execute(transaction) {
+                Ok(fee) => fees = fees.saturating_add(fee),
+                Err(e) => return Err(BlockError::InvalidTransaction(transaction.hash(), e)),
+            }
+        }
+        if let Some(coinbase) = coinbase {
+            let hash = transactions[0].hash();
+            let height = self.heights[&parent] + 1;
+            if coinbase.height != height {
+                let e = TransactionError::CoinbaseHeight { expected: height, found: coinbase.height };
+                return Err(BlockError::InvalidTransaction(hash, e));
+            }
+            let allowed = self.params.block_reward(height).saturating_add(fees);
+            if coinbase.value > allowed {
+                let e = TransactionError::ExcessiveCoinbase { allowed, found: coinbase.value };
+                return Err(BlockError::InvalidTransaction(hash, e));
+            }
+            state.reward(hash, coinbase);
+        }
+        Ok(state)
+    }
+
+    /// Find the blocks between two tips and their last common ancestor
+    fn reorg(&self, old_tip: H256, new_tip: H256) -> Reorg {
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        let mut old = old_tip;
+        let mut new = new_tip;
+        while self.heights[&old] > self.heights[&new] {
+            disconnected.push(old);
+            old = self.blocks[&old].get_parent();
+        }
+        while self.heights[&new] > self.heights[&old] {
+            connected.push(new);
+            new = self.blocks[&new].get_parent();
+        }
+        while old != new {
+            disconnected.push(old);
+            old = self.blocks[&old].get_parent();
+            connected.push(new);
+            new = self.blocks[&new].get_parent();
+        }
+        connected.reverse();
+        Reorg { disconnected, connected }
+    }
+
+    /// Get the transactions of the blocks disconnected by a reorg that are still valid on top of
+    /// the current tip, in the order they were originally executed
+    pub fn orphaned_transactions(&self, reorg: &Reorg) -> Vec<SignedTransaction> {
+        let mut state = self.states[&self.tip].clone();
+        let mut orphaned = Vec::new();
+        for hash in reorg.disconnected.iter().rev() {
+            for transaction in &self.blocks[hash].data.data {
+                if state.execute(transaction).is_ok() {
+                    orphaned.push(transaction.clone());
+                }
+            }
         }
+        orphaned
+    }
+
+    /// Get the height of a block, the genesis block being at height 0
+    pub fn height(&self, hash: &H256) -> Option<u128> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Get the total work of the chain ending at the block with the given hash
+    pub fn chainwork(&self, hash: &H256) -> Option<H256> {
+        self.chainwork.get(hash).copied()
     }
 
     /// Get the hash of the last block in the longest chain
@@ -51,6 +257,52 @@ impl Blockchain {
         self.tip
     }
 
+    /// Get the hash of the genesis block, which identifies the chain
+    pub fn genesis(&self) -> H256 {
+        self.genesis
+    }
+
+    /// Get the ledger model the chain was configured with
+    pub fn model(&self) -> LedgerModel {
+        self.model
+    }
+
+    /// Get the consensus parameters of the chain
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    /// Get the target that a new block extending the given parent must meet.
+    /// Every `retarget_interval` blocks, the target of the parent is scaled by the time the last
+    /// interval took over the time it should have taken, clamped by `max_adjustment`.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let params = &self.params;
+        let parent_block = &self.blocks[parent];
+        let height = self.heights[parent] + 1;
+        if params.retarget_interval == 0 || !height.is_multiple_of(u128::from(params.retarget_interval)) {
+            return parent_block.get_difficulty();
+        }
+        // walk back to the block that ended the previous interval, or to the genesis block
+        let mut first = *parent;
+        let mut intervals = 0;
+        while intervals < params.retarget_interval && self.heights[&first] > 0 {
+            first = self.blocks[&first].get_parent();
+            intervals += 1;
+        }
+        let expected = intervals.saturating_mul(params.target_block_time).max(1);
+        let elapsed = parent_block.header.timestamp.saturating_sub(self.blocks[&first].header.timestamp);
+        let elapsed = u64::try_from(elapsed).unwrap_or(u64::MAX);
+        // the target never drops to zero, which no hash could meet
+        let elapsed = elapsed.max((expected / params.max_adjustment).max(1)).min(expected.saturating_mul(params.max_adjustment));
+        let difficulty = parent_block.get_difficulty().scale(elapsed, expected);
+        difficulty.min(params.initial_difficulty)
+    }
+
+    /// Get the ledger state after executing the block with the given hash
+    pub fn state(&self, hash: &H256) -> Option<&State> {
+        self.states.get(hash)
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut vec: Vec<H256> = Vec::new();
@@ -72,16 +324,227 @@ mod tests {
     use super::*;
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use super::state::generate_signed_transaction;
+    use crate::types::address::Address;
+    use ring::signature::KeyPair;
 
     #[test]
     fn insert_one() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
 
     }
+
+    #[test]
+    fn state_per_block() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block).unwrap();
+        assert!(blockchain.state(&genesis_hash).is_some());
+        assert!(blockchain.state(&block.hash()).is_some());
+        assert!(blockchain.state(&generate_random_block(&genesis_hash).hash()).is_none());
+    }
+
+    #[test]
+    fn reject_overdraft() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut block = generate_random_block(&genesis_hash);
+        let signed = generate_signed_transaction(&key_pair::random(), [1u8; 20].into(), 1, 1);
+        block.data.data.push(signed.clone());
+        let expected = BlockError::InvalidTransaction(
+            signed.hash(),
+            TransactionError::InsufficientBalance { balance: 0, value: 1 },
+        );
+        assert_eq!(blockchain.insert(&block), Err(expected));
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert!(!blockchain.blocks.contains_key(&block.hash()));
+    }
+
+    #[test]
+    fn retarget() {
+        let spec = GenesisSpec::from_json(r#"{
+            "timestamp": 1000,
+            "difficulty": "0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "retarget_interval": 2,
+            "target_block_time": 100
+        }"#).unwrap();
+        let initial = spec.params.initial_difficulty;
+        let mut blockchain = Blockchain::from_genesis(&spec, LedgerModel::Account);
+        // blocks twice as fast as expected halve the target
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 1050;
+        block.header.difficulty = blockchain.next_difficulty(&blockchain.tip());
+        assert_eq!(block.header.difficulty, initial);
+        blockchain.insert(&block).unwrap();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 1100;
+        block.header.difficulty = blockchain.next_difficulty(&blockchain.tip());
+        assert_eq!(block.header.difficulty, initial.scale(1, 2));
+        blockchain.insert(&block).unwrap();
+        // no adjustment within an interval
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 1_000_000;
+        block.header.difficulty = blockchain.next_difficulty(&blockchain.tip());
+        assert_eq!(block.header.difficulty, initial.scale(1, 2));
+        blockchain.insert(&block).unwrap();
+        // slow blocks are clamped to the easiest target
+        assert_eq!(blockchain.next_difficulty(&blockchain.tip()), initial);
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 1_000_000;
+        block.header.difficulty = initial;
+        blockchain.insert(&block).unwrap();
+        // an interval taking no time at all is clamped too, never to a zero target
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 1_000_000;
+        block.header.difficulty = initial;
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.next_difficulty(&blockchain.tip()), initial.scale(1, 4));
+    }
+
+    #[test]
+    fn most_work_tip() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy: H256 = [255u8; 32].into();
+        let hard: H256 = hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        // two easy blocks do not outweigh a single hard block
+        let mut first = generate_random_block(&genesis_hash);
+        first.header.difficulty = easy;
+        blockchain.insert(&first).unwrap();
+        let mut second = generate_random_block(&first.hash());
+        second.header.difficulty = easy;
+        blockchain.insert(&second).unwrap();
+        assert_eq!(blockchain.tip(), second.hash());
+        let mut heavy = generate_random_block(&genesis_hash);
+        heavy.header.difficulty = hard;
+        blockchain.insert(&heavy).unwrap();
+        assert_eq!(blockchain.tip(), heavy.hash());
+        // ties keep the block seen first
+        let mut tie = generate_random_block(&genesis_hash);
+        tie.header.difficulty = hard;
+        blockchain.insert(&tie).unwrap();
+        assert_eq!(blockchain.tip(), heavy.hash());
+        let genesis_work = blockchain.chainwork(&genesis_hash).unwrap();
+        assert_eq!(blockchain.chainwork(&heavy.hash()), Some(genesis_work.saturating_add(&hard.work())));
+    }
+
+    #[test]
+    fn reorg_orphans_transactions() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let spec = GenesisSpec::from_json(&format!(
+            r#"{{"allocations": [{{"address": "{}", "value": 10}}]}}"#, sender
+        )).unwrap();
+        let mut blockchain = Blockchain::from_genesis(&spec, LedgerModel::Account);
+        let genesis_hash = blockchain.tip();
+        let orphaned = generate_signed_transaction(&key, [1u8; 20].into(), 3, 1);
+        let mut block = generate_random_block(&genesis_hash);
+        block.data.data.push(orphaned.clone());
+        let extension = blockchain.insert(&block).unwrap().unwrap();
+        assert_eq!(extension, Reorg { disconnected: vec![], connected: vec![block.hash()] });
+
+        // a heavier branch without the transaction replaces the block
+        let mut first = generate_random_block(&genesis_hash);
+        first.header.difficulty = block.header.difficulty;
+        assert_eq!(blockchain.insert(&first).unwrap(), None);
+        let mut second = generate_random_block(&first.hash());
+        second.header.difficulty = block.header.difficulty;
+        let reorg = blockchain.insert(&second).unwrap().unwrap();
+        assert_eq!(reorg, Reorg { disconnected: vec![block.hash()], connected: vec![first.hash(), second.hash()] });
+        let transactions: Vec<H256> = blockchain.orphaned_transactions(&reorg).iter().map(|t| t.hash()).collect();
+        assert_eq!(transactions, vec![orphaned.hash()]);
+    }
+
+    #[test]
+    fn reopen_datadir() {
+        let datadir = std::env::temp_dir().join(format!("bitcoin-chain-{}", crate::types::hash::generate_random_hash()));
+        let spec = GenesisSpec::default();
+        let mut blockchain = Blockchain::open(&datadir, &spec, LedgerModel::Account).unwrap();
+        let block = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block).unwrap();
+        let child = generate_random_block(&blockchain.tip());
+        blockchain.insert(&child).unwrap();
+        drop(blockchain);
+        let blockchain = Blockchain::open(&datadir, &spec, LedgerModel::Account).unwrap();
+        assert_eq!(blockchain.tip(), child.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 3);
+        assert!(blockchain.state(&block.hash()).is_some());
+        std::fs::remove_dir_all(&datadir).unwrap();
+    }
+
+    #[test]
+    fn genesis_allocations() {
+        let spec = GenesisSpec::from_json(r#"{
+            "timestamp": 42,
+            "allocations": [{"address": "0101010101010101010101010101010101010101", "value": 50}]
+        }"#).unwrap();
+        let address = [1u8; 20].into();
+        let account = Blockchain::from_genesis(&spec, LedgerModel::Account);
+        let utxo = Blockchain::from_genesis(&spec, LedgerModel::Utxo);
+        assert_eq!(account.tip(), Blockchain::from_genesis(&spec, LedgerModel::Account).tip());
+        assert_ne!(account.tip(), Blockchain::new().tip());
+        // the genesis hash also tells apart the ledger models and the consensus parameters
+        assert_ne!(account.tip(), utxo.tip());
+        let mut rewarded = spec.clone();
+        rewarded.params.block_reward += 1;
+        assert_ne!(account.tip(), Blockchain::from_genesis(&rewarded, LedgerModel::Account).tip());
+        assert_eq!(account.state(&account.tip()).unwrap().balance(&address), 50);
+        assert_eq!(utxo.state(&utxo.tip()).unwrap().balance(&address), 50);
+    }
+
+    #[test]
+    fn coinbase_reward() {
+        use crate::types::transaction::Coinbase;
+        use crate::types::utxo::UtxoTransaction;
+
+        let spec = GenesisSpec::from_json(r#"{"block_reward": 8, "halving_interval": 2}"#).unwrap();
+        assert_eq!(spec.params.block_reward(1), 8);
+        assert_eq!(spec.params.block_reward(2), 4);
+        assert_eq!(spec.params.block_reward(400), 0);
+        let key = key_pair::random();
+        let miner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut blockchain = Blockchain::from_genesis(&spec, LedgerModel::Utxo);
+        let mut block = generate_random_block(&blockchain.tip());
+        let coinbase = SignedTransaction::coinbase(Coinbase { receiver: miner, value: 8, height: 1 });
+        block.data.data.push(coinbase.clone());
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.state(&block.hash()).unwrap().balance(&miner), 8);
+
+        // the reward is halved at height 2, the spender keeps 3 of its 8 and leaves 5 as fee
+        let input = Input { prev_tx: coinbase.hash(), index: 0 };
+        let outputs = vec![Output { receiver: miner, value: 3 }];
+        let spend = SignedTransaction::from_payload(Payload::Utxo(UtxoTransaction { inputs: vec![input], outputs }), &key);
+        let mut child = generate_random_block(&block.hash());
+        child.data.data.push(SignedTransaction::coinbase(Coinbase { receiver: miner, value: 10, height: 2 }));
+        child.data.data.push(spend.clone());
+        let expected = BlockError::InvalidTransaction(
+            child.data.data[0].hash(),
+            TransactionError::ExcessiveCoinbase { allowed: 9, found: 10 },
+        );
+        assert_eq!(blockchain.insert(&child), Err(expected));
+        child.data.data[0] = SignedTransaction::coinbase(Coinbase { receiver: miner, value: 9, height: 3 });
+        assert!(matches!(
+            blockchain.insert(&child),
+            Err(BlockError::InvalidTransaction(_, TransactionError::CoinbaseHeight { expected: 2, found: 3 }))
+        ));
+        child.data.data[0] = SignedTransaction::coinbase(Coinbase { receiver: miner, value: 9, height: 2 });
+        blockchain.insert(&child).unwrap();
+        assert_eq!(blockchain.state(&child.hash()).unwrap().balance(&miner), 12);
+
+        // a coinbase anywhere but first is not valid
+        let mut misplaced = generate_random_block(&child.hash());
+        misplaced.data.data.push(SignedTransaction::coinbase(Coinbase { receiver: miner, value: 2, height: 3 }));
+        let second = SignedTransaction::coinbase(Coinbase { receiver: miner, value: 0, height: 3 });
+        misplaced.data.data.push(second.clone());
+        let expected = BlockError::InvalidTransaction(second.hash(), TransactionError::UnexpectedCoinbase);
+        assert_eq!(blockchain.insert(&misplaced), Err(expected));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -0,0 +1,80 @@
+use crate::types::hash::H256;
+use serde::{Deserialize, Serialize};
+
+/// Consensus parameters of a chain, owned by the `Blockchain` and shared by every node
+/// started from the same genesis spec.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConsensusParams {
+    /// target of the genesis block, and the easiest target the chain can retarget to
+    #[serde(rename = "difficulty", deserialize_with = "super::genesis::deserialize_hash")]
+    pub initial_difficulty: H256,
+    /// number of blocks between two difficulty adjustments, 0 to never adjust
+    pub retarget_interval: u64,
+    /// expected time between two blocks, in milliseconds
+    pub target_block_time: u64,
+    /// largest factor by which the target can change in one adjustment
+    pub max_adjustment: u64,
+    /// number of past blocks whose median timestamp a new block must exceed
+    pub median_time_span: usize,
+    /// how far in the future the timestamp of a block may be, in milliseconds
+    pub max_future_drift: u64,
+    /// largest serialized size of a block, in bytes
+    pub max_block_size: u64,
+    /// largest number of transactions in a block
+    pub max_block_transactions: usize,
+    /// value created by the coinbase of the first blocks
+    pub block_reward: u64,
+    /// number of blocks after which the block reward is halved, 0 to never halve it
+    pub halving_interval: u64,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            initial_difficulty: [10u8; 32].into(),
+            retarget_interval: 20,
+            target_block_time: 1000,
+            max_adjustment: 4,
+            median_time_span: 11,
+            max_future_drift: 2 * 60 * 60 * 1000,
+            max_block_size: 1_000_000,
+            max_block_transactions: 4096,
+            block_reward: 50,
+            halving_interval: 210_000,
+        }
+    }
+}
+
+impl ConsensusParams {
+    /// Check that the parameters describe a chain the node can run
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_adjustment == 0 {
+            return Err("max_adjustment must be at least 1".to_string());
+        }
+        if self.median_time_span == 0 {
+            return Err("median_time_span must be at least 1".to_string());
+        }
+        if self.target_block_time == 0 {
+            return Err("target_block_time must be at least 1".to_string());
+        }
+        // an interval shorter than max_adjustment milliseconds could not be scaled down by it
+        if self.retarget_interval != 0 && self.retarget_interval.saturating_mul(self.target_block_time) < self.max_adjustment {
+            return Err("retarget_interval * target_block_time must be at least max_adjustment".to_string());
+        }
+        Ok(())
+    }
+
+    /// Get the value a coinbase may create, on top of the fees, in the block at the given height
+    pub fn block_reward(&self, height: u128) -> u128 {
+        let reward = u128::from(self.block_reward);
+        if self.halving_interval == 0 {
+            return reward;
+        }
+        let halvings = height / u128::from(self.halving_interval);
+        if halvings >= 128 {
+            return 0;
+        }
+        reward >> halvings
+    }
+}
@@ -0,0 +1,288 @@
+use crate::types::address::Address;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{Coinbase, Payload, SignedTransaction, Transaction, verify};
+use crate::types::utxo::{Input, Output};
+use super::utxo::UtxoState;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The transaction model of a chain, chosen when the chain is configured.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerModel {
+    Account,
+    Utxo,
+}
+
+impl std::str::FromStr for LedgerModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "account" => Ok(LedgerModel::Account),
+            "utxo" => Ok(LedgerModel::Utxo),
+            _ => Err(format!("unknown ledger model {}, expected account or utxo", s)),
+        }
+    }
+}
+
+/// Ledger state of a chain, in the model the chain was configured with.
+#[derive(Debug, Clone)]
+pub enum State {
+    Account(AccountState),
+    Utxo(UtxoState),
+}
+
+/// Account-based ledger state: the nonce and balance of every address.
+#[derive(Debug, Default, Clone)]
+pub struct AccountState {
+    // address -> (nonce, balance)
+    accounts: HashMap<Address, (u128, u128)>,
+}
+
+/// Reasons for which a transaction cannot be executed on top of a state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// the signature does not match the transaction and public key
+    InvalidSignature,
+    /// the transaction does not belong to the ledger model of the chain
+    WrongModel,
+    /// the sender address, or the owner of a spent output, is not derived from the signing public key
+    SenderMismatch,
+    /// the sender cannot cover the value and fee of the transaction
+    InsufficientBalance { balance: u128, value: u128 },
+    /// the nonce is not exactly the sender's nonce + 1
+    InvalidNonce { expected: u128, found: u128 },
+    /// the transaction does not spend any output
+    NoInputs,
+    /// the transaction spends the same output twice
+    DuplicateInput(Input),
+    /// the spent output does not exist or is already spent
+    UnknownInput(Input),
+    /// a coinbase anywhere else than in the first position of a block
+    UnexpectedCoinbase,
+    /// the coinbase claims more than the block reward and the fees
+    ExcessiveCoinbase { allowed: u128, found: u128 },
+    /// the coinbase does not carry the height of its block
+    CoinbaseHeight { expected: u128, found: u128 },
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::WrongModel => write!(f, "transaction of the wrong ledger model"),
+            TransactionError::SenderMismatch => write!(f, "sender does not match public key"),
+            TransactionError::InsufficientBalance { balance, value } => {
+                write!(f, "insufficient balance: has {}, sends {}", balance, value)
+            }
+            TransactionError::InvalidNonce { expected, found } => {
+                write!(f, "invalid nonce: expected {}, found {}", expected, found)
+            }
+            TransactionError::NoInputs => write!(f, "no inputs"),
+            TransactionError::DuplicateInput(input) => write!(f, "output {} spent twice", input),
+            TransactionError::UnknownInput(input) => write!(f, "unknown or spent output {}", input),
+            TransactionError::UnexpectedCoinbase => write!(f, "coinbase outside the first position of a block"),
+            TransactionError::ExcessiveCoinbase { allowed, found } => {
+                write!(f, "coinbase of {} above the allowed {}", found, allowed)
+            }
+            TransactionError::CoinbaseHeight { expected, found } => {
+                write!(f, "coinbase height {} instead of {}", found, expected)
+            }
+        }
+    }
+}
+
+impl TransactionError {
+    /// Check whether the transaction can never become valid, whatever the state it is executed on
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            TransactionError::InvalidSignature
+                | TransactionError::WrongModel
+                | TransactionError::SenderMismatch
+                | TransactionError::NoInputs
+                | TransactionError::DuplicateInput(_)
+                | TransactionError::UnexpectedCoinbase
+        )
+    }
+}
+
+impl State {
+    /// Create the empty state of a ledger model
+    pub fn new(model: LedgerModel) -> Self {
+        match model {
+            LedgerModel::Account => State::Account(AccountState::new()),
+            LedgerModel::Utxo => State::Utxo(UtxoState::new()),
+        }
+    }
+
+    /// Get the ledger model of the state
+    pub fn model(&self) -> LedgerModel {
+        match self {
+            State::Account(_) => LedgerModel::Account,
+            State::Utxo(_) => LedgerModel::Utxo,
+        }
+    }
+
+    /// Get the nonce of the last transaction sent by an address, always 0 in the UTXO model
+    pub fn nonce(&self, address: &Address) -> u128 {
+        match self {
+            State::Account(state) => state.nonce(address),
+            State::Utxo(_) => 0,
+        }
+    }
+
+    /// Get the balance of an address
+    pub fn balance(&self, address: &Address) -> u128 {
+        match self {
+            State::Account(state) => state.balance(address),
+            State::Utxo(state) => state.balance(address),
+        }
+    }
+
+    /// Check a signed transaction against this state without applying it. Returns the fee of the
+    /// transaction. A coinbase is never valid here, it is credited by `reward`.
+    pub fn check(&self, signed: &SignedTransaction) -> Result<u128, TransactionError> {
+        if signed.is_coinbase() {
+            return Err(TransactionError::UnexpectedCoinbase);
+        }
+        if !verify(&signed.transaction, &signed.pubkey, &signed.signature) {
+            return Err(TransactionError::InvalidSignature);
+        }
+        match (self, &signed.transaction) {
+            (State::Account(state), Payload::Account(transaction)) => state.check(&signed.signer(), transaction),
+            (State::Utxo(state), Payload::Utxo(transaction)) => state.check(&signed.signer(), transaction),
+            _ => Err(TransactionError::WrongModel),
+        }
+    }
+
+    /// Apply a signed transaction and return its fee.
+    /// The state is left untouched if the transaction is not valid.
+    pub fn execute(&mut self, signed: &SignedTransaction) -> Result<u128, TransactionError> {
+        let fee = self.check(signed)?;
+        match (self, &signed.transaction) {
+            (State::Account(state), Payload::Account(transaction)) => state.apply(transaction),
+            (State::Utxo(state), Payload::Utxo(transaction)) => state.apply(signed.hash(), transaction),
+            _ => unreachable!(),
+        }
+        Ok(fee)
+    }
+
+    /// Credit the value of a coinbase, with the given hash, to its receiver
+    pub fn reward(&mut self, hash: H256, coinbase: &Coinbase) {
+        match self {
+            State::Account(state) => state.credit(&coinbase.receiver, coinbase.value),
+            State::Utxo(state) => {
+                let output = Output { receiver: coinbase.receiver, value: coinbase.value };
+                state.add_output(Input { prev_tx: hash, index: 0 }, output);
+            }
+        }
+    }
+}
+
+impl AccountState {
+    /// Create an empty state, in which every account has nonce 0 and balance 0
+    pub fn new() -> Self {
+        AccountState { accounts: HashMap::new() }
+    }
+
+    /// Get the nonce of the last transaction sent by an address
+    pub fn nonce(&self, address: &Address) -> u128 {
+        self.accounts.get(address).map(|account| account.0).unwrap_or(0)
+    }
+
+    /// Get the balance of an address
+    pub fn balance(&self, address: &Address) -> u128 {
+        self.accounts.get(address).map(|account| account.1).unwrap_or(0)
+    }
+
+    /// Add value to the balance of an address
+    pub fn credit(&mut self, address: &Address, value: u128) {
+        let account = self.accounts.entry(*address).or_insert((0, 0));
+        account.1 += value;
+    }
+
+    /// Check a transfer signed by the given address against this state, and return its fee
+    pub fn check(&self, signer: &Address, transaction: &Transaction) -> Result<u128, TransactionError> {
+        if *signer != transaction.sender {
+            return Err(TransactionError::SenderMismatch);
+        }
+        let expected = self.nonce(&transaction.sender) + 1;
+        if transaction.nonce != expected {
+            return Err(TransactionError::InvalidNonce { expected, found: transaction.nonce });
+        }
+        let balance = self.balance(&transaction.sender);
+        let value = transaction.value.saturating_add(transaction.fee);
+        if balance < value {
+            return Err(TransactionError::InsufficientBalance { balance, value });
+        }
+        Ok(transaction.fee)
+    }
+
+    /// Move the value of a checked transfer from the sender to the receiver, and take the fee
+    /// from the sender
+    pub fn apply(&mut self, transaction: &Transaction) {
+        let balance = self.balance(&transaction.sender);
+        let balance = balance - transaction.value - transaction.fee;
+        self.accounts.insert(transaction.sender, (transaction.nonce, balance));
+        self.credit(&transaction.receiver, transaction.value);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+pub fn generate_signed_transaction(key: &ring::signature::Ed25519KeyPair, receiver: Address, value: u128, nonce: u128) -> SignedTransaction {
+    generate_signed_transaction_with_fee(key, receiver, value, nonce, 0)
+}
+
+#[cfg(any(test, test_utilities))]
+pub fn generate_signed_transaction_with_fee(key: &ring::signature::Ed25519KeyPair, receiver: Address, value: u128, nonce: u128, fee: u128) -> SignedTransaction {
+    use ring::signature::KeyPair;
+
+    let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+    SignedTransaction::from_payload(Payload::Account(Transaction { sender, receiver, value, nonce, fee }), key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn execute_transfer() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from([7u8; 20]);
+        let mut accounts = AccountState::new();
+        accounts.credit(&sender, 15);
+        let mut state = State::Account(accounts);
+        let signed = generate_signed_transaction_with_fee(&key, receiver, 10, 1, 2);
+        assert_eq!(state.execute(&signed), Ok(2));
+        assert_eq!(state.balance(&sender), 3);
+        assert_eq!(state.balance(&receiver), 10);
+        assert_eq!(state.nonce(&sender), 1);
+    }
+
+    #[test]
+    fn execute_invalid() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from([7u8; 20]);
+        let mut accounts = AccountState::new();
+        accounts.credit(&sender, 5);
+        let mut state = State::Account(accounts);
+        let overdraft = generate_signed_transaction(&key, receiver, 10, 1);
+        assert_eq!(state.execute(&overdraft), Err(TransactionError::InsufficientBalance { balance: 5, value: 10 }));
+        let replayed = generate_signed_transaction(&key, receiver, 1, 0);
+        assert_eq!(state.execute(&replayed), Err(TransactionError::InvalidNonce { expected: 1, found: 0 }));
+        let mut forged = generate_signed_transaction(&key, receiver, 1, 1);
+        if let Payload::Account(transaction) = &mut forged.transaction {
+            transaction.value = 2;
+        }
+        assert_eq!(state.execute(&forged), Err(TransactionError::InvalidSignature));
+        assert_eq!(state.balance(&sender), 5);
+        assert_eq!(state.balance(&receiver), 0);
+        let mut utxo = State::new(LedgerModel::Utxo);
+        assert_eq!(utxo.execute(&generate_signed_transaction(&key, receiver, 0, 1)), Err(TransactionError::WrongModel));
+    }
+}
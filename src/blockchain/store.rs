@@ -0,0 +1,106 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// size of an index record: block hash followed by the big endian offset of the block
+const INDEX_RECORD_SIZE: usize = 40;
+
+/// On-disk block storage: an append-only file of length-prefixed blocks, plus an index
+/// recording the hash and offset of every block. A block only counts as stored once its
+/// index record is written, so a crash in the middle of an append loses at most that block.
+pub struct BlockStore {
+    blocks: File,
+    index: File,
+}
+
+impl BlockStore {
+    /// Open the store in the given directory, creating it if needed, and return the stored
+    /// blocks in the order they were appended
+    pub fn open(datadir: &Path) -> io::Result<(Self, Vec<Block>)> {
+        fs::create_dir_all(datadir)?;
+        let mut blocks = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(datadir.join("blocks.dat"))?;
+        let mut index = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(datadir.join("index.dat"))?;
+
+        let mut records = Vec::new();
+        index.read_to_end(&mut records)?;
+        let mut stored = Vec::new();
+        let mut end: u64 = 0;
+        for record in records.chunks_exact(INDEX_RECORD_SIZE) {
+            let hash: [u8; 32] = record[0..32].try_into().unwrap();
+            let hash: H256 = hash.into();
+            let offset = u64::from_be_bytes(record[32..40].try_into().unwrap());
+            let block = match Self::read_block(&mut blocks, offset) {
+                Ok(block) if block.hash() == hash => block,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("block {} at offset {} is corrupted", hash, offset),
+                    ))
+                }
+            };
+            end = blocks.stream_position()?;
+            stored.push(block);
+        }
+        // drop partially written index records and blocks that were never indexed
+        index.set_len((stored.len() * INDEX_RECORD_SIZE) as u64)?;
+        blocks.set_len(end)?;
+        blocks.seek(SeekFrom::End(0))?;
+        index.seek(SeekFrom::End(0))?;
+        Ok((BlockStore { blocks, index }, stored))
+    }
+
+    /// Append a block to the store
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let offset = self.blocks.seek(SeekFrom::End(0))?;
+        let encoded = bincode::serialize(block).unwrap();
+        self.blocks.write_all(&(encoded.len() as u32).to_be_bytes())?;
+        self.blocks.write_all(&encoded)?;
+        self.blocks.sync_data()?;
+        let mut record = Vec::with_capacity(INDEX_RECORD_SIZE);
+        record.extend_from_slice(block.hash().as_ref());
+        record.extend_from_slice(&offset.to_be_bytes());
+        self.index.write_all(&record)?;
+        self.index.sync_data()
+    }
+
+    fn read_block(blocks: &mut File, offset: u64) -> io::Result<Block> {
+        blocks.seek(SeekFrom::Start(offset))?;
+        let mut size_buffer = [0u8; 4];
+        blocks.read_exact(&mut size_buffer)?;
+        let mut buffer = vec![0u8; u32::from_be_bytes(size_buffer) as usize];
+        blocks.read_exact(&mut buffer)?;
+        bincode::deserialize(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn reopen() {
+        let datadir = std::env::temp_dir().join(format!("bitcoin-store-{}", generate_random_hash()));
+        let first = generate_random_block(&generate_random_hash());
+        let second = generate_random_block(&first.hash());
+        {
+            let (mut store, stored) = BlockStore::open(&datadir).unwrap();
+            assert!(stored.is_empty());
+            store.append(&first).unwrap();
+            store.append(&second).unwrap();
+        }
+        // a block written without its index record is dropped
+        {
+            let mut blocks = OpenOptions::new().append(true).open(datadir.join("blocks.dat")).unwrap();
+            blocks.write_all(&[0, 0, 1]).unwrap();
+        }
+        let (_, stored) = BlockStore::open(&datadir).unwrap();
+        let hashes: Vec<H256> = stored.iter().map(|block| block.hash()).collect();
+        assert_eq!(hashes, vec![first.hash(), second.hash()]);
+        fs::remove_dir_all(&datadir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use crate::types::utxo::{Input, Output, UtxoTransaction};
+use super::state::TransactionError;
+use std::collections::{HashMap, HashSet};
+
+/// UTXO-based ledger state: the set of unspent transaction outputs.
+#[derive(Debug, Default, Clone)]
+pub struct UtxoState {
+    outputs: HashMap<Input, Output>,
+}
+
+impl UtxoState {
+    /// Create an empty UTXO set
+    pub fn new() -> Self {
+        UtxoState { outputs: HashMap::new() }
+    }
+
+    /// Get the unspent output referenced by an input
+    pub fn get(&self, input: &Input) -> Option<&Output> {
+        self.outputs.get(input)
+    }
+
+    /// Add an unspent output to the set
+    pub fn add_output(&mut self, input: Input, output: Output) {
+        self.outputs.insert(input, output);
+    }
+
+    /// Get all unspent outputs owned by an address
+    pub fn unspent(&self, address: &Address) -> Vec<(Input, Output)> {
+        self.outputs
+            .iter()
+            .filter(|(_, output)| output.receiver == *address)
+            .map(|(input, output)| (*input, output.clone()))
+            .collect()
+    }
+
+    /// Get the total value of the unspent outputs owned by an address
+    pub fn balance(&self, address: &Address) -> u128 {
+        self.unspent(address).iter().map(|(_, output)| output.value).sum()
+    }
+
+    /// Check a transaction signed by the given address against the UTXO set.
+    /// Returns the fee, the value of the inputs not claimed by the outputs.
+    pub fn check(&self, signer: &Address, transaction: &UtxoTransaction) -> Result<u128, TransactionError> {
+        if transaction.inputs.is_empty() {
+            return Err(TransactionError::NoInputs);
+        }
+        let mut spent = HashSet::new();
+        let mut balance: u128 = 0;
+        for input in &transaction.inputs {
+            if !spent.insert(*input) {
+                return Err(TransactionError::DuplicateInput(*input));
+            }
+            let output = match self.outputs.get(input) {
+                Some(output) => output,
+                None => return Err(TransactionError::UnknownInput(*input)),
+            };
+            // every spent output must belong to the signer
+            if output.receiver != *signer {
+                return Err(TransactionError::SenderMismatch);
+            }
+            balance = balance.saturating_add(output.value);
+        }
+        let value = transaction.outputs.iter().fold(0u128, |sum, output| sum.saturating_add(output.value));
+        if balance < value {
+            return Err(TransactionError::InsufficientBalance { balance, value });
+        }
+        Ok(balance - value)
+    }
+
+    /// Spend the inputs of a checked transaction and add its outputs, indexed by the transaction hash
+    pub fn apply(&mut self, hash: H256, transaction: &UtxoTransaction) {
+        for input in &transaction.inputs {
+            self.outputs.remove(input);
+        }
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            self.outputs.insert(Input { prev_tx: hash, index: index as u32 }, output.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::state::State;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::transaction::{Payload, SignedTransaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed(key: &Ed25519KeyPair, inputs: Vec<Input>, outputs: Vec<Output>) -> SignedTransaction {
+        SignedTransaction::from_payload(Payload::Utxo(UtxoTransaction { inputs, outputs }), key)
+    }
+
+    #[test]
+    fn spend_outputs() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from([3u8; 20]);
+        let funding = Input { prev_tx: [1u8; 32].into(), index: 0 };
+        let mut utxos = UtxoState::new();
+        utxos.add_output(funding, Output { receiver: owner, value: 10 });
+        let mut state = State::Utxo(utxos);
+
+        let outputs = vec![Output { receiver, value: 6 }, Output { receiver: owner, value: 4 }];
+        let transaction = signed(&key, vec![funding], outputs);
+        assert_eq!(state.execute(&transaction), Ok(0));
+        assert_eq!(state.balance(&owner), 4);
+        assert_eq!(state.balance(&receiver), 6);
+        if let State::Utxo(utxos) = &state {
+            assert!(utxos.get(&funding).is_none());
+            assert_eq!(utxos.get(&Input { prev_tx: transaction.hash(), index: 0 }).unwrap().value, 6);
+        }
+
+        // the funding output is already spent
+        let double_spend = signed(&key, vec![funding], vec![]);
+        assert_eq!(state.execute(&double_spend), Err(TransactionError::UnknownInput(funding)));
+    }
+
+    #[test]
+    fn reject_invalid_spends() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let funding = Input { prev_tx: [1u8; 32].into(), index: 0 };
+        let mut utxos = UtxoState::new();
+        utxos.add_output(funding, Output { receiver: owner, value: 10 });
+        let mut state = State::Utxo(utxos);
+
+        let overspend = signed(&key, vec![funding], vec![Output { receiver: owner, value: 11 }]);
+        assert_eq!(state.execute(&overspend), Err(TransactionError::InsufficientBalance { balance: 10, value: 11 }));
+        let duplicate = signed(&key, vec![funding, funding], vec![]);
+        assert_eq!(state.execute(&duplicate), Err(TransactionError::DuplicateInput(funding)));
+        let stolen = signed(&key_pair::random(), vec![funding], vec![]);
+        assert_eq!(state.execute(&stolen), Err(TransactionError::SenderMismatch));
+        assert_eq!(state.execute(&signed(&key, vec![], vec![])), Err(TransactionError::NoInputs));
+        assert_eq!(state.balance(&owner), 10);
+    }
+}
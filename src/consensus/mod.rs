@@ -0,0 +1,207 @@
+use crate::blockchain::{BlockError, Blockchain};
+use crate::blockchain::state::TransactionError;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::MerkleTree;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Reasons for which a block is invalid on top of a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// the parent of the block is not in the chain
+    UnknownParent(H256),
+    /// the header does not claim the target required after its parent
+    WrongDifficulty { expected: H256, found: H256 },
+    /// the hash of the block does not meet its target
+    InsufficientWork,
+    /// the merkle root of the header does not match the transactions
+    MerkleRootMismatch { expected: H256, found: H256 },
+    /// the timestamp is not above the median timestamp of the past blocks
+    TimestampTooOld { median: u128, found: u128 },
+    /// the timestamp is too far in the future
+    TimestampTooNew { limit: u128, found: u128 },
+    /// the serialized block is larger than allowed
+    TooLarge { limit: u64, size: u64 },
+    /// the block holds more transactions than allowed
+    TooManyTransactions { limit: usize, count: usize },
+    /// the block holds the same transaction twice
+    DuplicateTransaction(H256),
+    /// a transaction, identified by its hash, cannot be executed on the parent's state
+    InvalidTransaction(H256, TransactionError),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            ValidationError::WrongDifficulty { expected, found } => {
+                write!(f, "difficulty {} differs from expected target {}", found, expected)
+            }
+            ValidationError::InsufficientWork => write!(f, "hash does not meet the target"),
+            ValidationError::MerkleRootMismatch { expected, found } => {
+                write!(f, "merkle root {} differs from the root of the transactions {}", found, expected)
+            }
+            ValidationError::TimestampTooOld { median, found } => {
+                write!(f, "timestamp {} is not above the median past timestamp {}", found, median)
+            }
+            ValidationError::TimestampTooNew { limit, found } => {
+                write!(f, "timestamp {} is after the limit {}", found, limit)
+            }
+            ValidationError::TooLarge { limit, size } => write!(f, "size {} above the limit {}", size, limit),
+            ValidationError::TooManyTransactions { limit, count } => {
+                write!(f, "{} transactions, above the limit {}", count, limit)
+            }
+            ValidationError::DuplicateTransaction(hash) => write!(f, "duplicate transaction {}", hash),
+            ValidationError::InvalidTransaction(hash, e) => write!(f, "transaction {}: {}", hash, e),
+        }
+    }
+}
+
+/// Check that a block is valid on top of its parent in the chain: header rules, body limits,
+/// and the execution of every transaction, coinbase included, on the parent's state.
+pub fn validate_block(chain: &Blockchain, block: &Block) -> Result<(), ValidationError> {
+    let parent = block.get_parent();
+    if !chain.blocks.contains_key(&parent) {
+        return Err(ValidationError::UnknownParent(parent));
+    }
+    validate_header(chain, block)?;
+    validate_body(chain, block)?;
+    match chain.execute(block) {
+        Ok(_) => Ok(()),
+        Err(BlockError::UnknownParent(parent)) => Err(ValidationError::UnknownParent(parent)),
+        Err(BlockError::InvalidTransaction(hash, e)) => Err(ValidationError::InvalidTransaction(hash, e)),
+    }
+}
+
+/// Check the proof of work and the timestamp of a block whose parent is in the chain
+pub fn validate_header(chain: &Blockchain, block: &Block) -> Result<(), ValidationError> {
+    let params = chain.params();
+    let expected = chain.next_difficulty(&block.get_parent());
+    if block.get_difficulty() != expected {
+        return Err(ValidationError::WrongDifficulty { expected, found: block.get_difficulty() });
+    }
+    if block.hash() > expected {
+        return Err(ValidationError::InsufficientWork);
+    }
+    let median = median_time_past(chain, &block.get_parent());
+    let timestamp = block.header.timestamp;
+    if timestamp <= median {
+        return Err(ValidationError::TimestampTooOld { median, found: timestamp });
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let limit = now + params.max_future_drift as u128;
+    if timestamp > limit {
+        return Err(ValidationError::TimestampTooNew { limit, found: timestamp });
+    }
+    Ok(())
+}
+
+/// Check the size limits, the merkle root and the uniqueness of the transactions of a block
+pub fn validate_body(chain: &Blockchain, block: &Block) -> Result<(), ValidationError> {
+    let params = chain.params();
+    let count = block.data.data.len();
+    if count > params.max_block_transactions {
+        return Err(ValidationError::TooManyTransactions { limit: params.max_block_transactions, count });
+    }
+    let size = bincode::serialized_size(block).unwrap();
+    if size > params.max_block_size {
+        return Err(ValidationError::TooLarge { limit: params.max_block_size, size });
+    }
+    let expected = MerkleTree::new(&block.data.data).root();
+    if block.header.merkle_root != expected {
+        return Err(ValidationError::MerkleRootMismatch { expected, found: block.header.merkle_root });
+    }
+    let mut seen = HashSet::new();
+    for transaction in &block.data.data {
+        let hash = transaction.hash();
+        if !seen.insert(hash) {
+            return Err(ValidationError::DuplicateTransaction(hash));
+        }
+    }
+    Ok(())
+}
+
+/// Get the median timestamp of the last `median_time_span` blocks ending at the given block
+pub fn median_time_past(chain: &Blockchain, hash: &H256) -> u128 {
+    let mut timestamps = Vec::new();
+    let mut current = *hash;
+    // the walk stops at the genesis block, whose parent is not in the chain
+    while let Some(block) = chain.blocks.get(&current) {
+        timestamps.push(block.header.timestamp);
+        if timestamps.len() >= chain.params().median_time_span {
+            break;
+        }
+        current = block.get_parent();
+    }
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::GenesisSpec;
+    use crate::blockchain::state::{LedgerModel, generate_signed_transaction};
+    use crate::types::block::generate_random_block;
+    use crate::types::key_pair;
+
+    /// a chain whose target every hash meets
+    fn easy_chain() -> Blockchain {
+        let mut spec = GenesisSpec::default();
+        spec.params.initial_difficulty = [255u8; 32].into();
+        spec.params.median_time_span = 3;
+        Blockchain::from_genesis(&spec, LedgerModel::Account)
+    }
+
+    #[test]
+    fn accept_valid_block() {
+        let chain = easy_chain();
+        let block = generate_random_block(&chain.tip());
+        assert_eq!(validate_block(&chain, &block), Ok(()));
+        let orphan = generate_random_block(&block.hash());
+        assert_eq!(validate_block(&chain, &orphan), Err(ValidationError::UnknownParent(block.hash())));
+    }
+
+    #[test]
+    fn reject_invalid_header() {
+        let chain = easy_chain();
+        let mut block = generate_random_block(&chain.tip());
+        block.header.difficulty = [0u8; 32].into();
+        assert!(matches!(validate_block(&chain, &block), Err(ValidationError::WrongDifficulty { .. })));
+        let mut block = generate_random_block(&chain.tip());
+        block.header.timestamp = 0;
+        assert_eq!(validate_block(&chain, &block), Err(ValidationError::TimestampTooOld { median: 0, found: 0 }));
+        block.header.timestamp = u128::MAX;
+        assert!(matches!(validate_block(&chain, &block), Err(ValidationError::TimestampTooNew { .. })));
+    }
+
+    #[test]
+    fn median_timestamp() {
+        let mut chain = easy_chain();
+        for timestamp in &[50, 10, 40, 30] {
+            let mut block = generate_random_block(&chain.tip());
+            block.header.timestamp = *timestamp;
+            chain.insert(&block).unwrap();
+        }
+        // the last three timestamps are 10, 40 and 30
+        assert_eq!(median_time_past(&chain, &chain.tip()), 30);
+    }
+
+    #[test]
+    fn reject_invalid_body() {
+        let chain = easy_chain();
+        let key = key_pair::random();
+        let mut block = generate_random_block(&chain.tip());
+        block.data.data.push(generate_signed_transaction(&key, [1u8; 20].into(), 0, 1));
+        assert!(matches!(validate_block(&chain, &block), Err(ValidationError::MerkleRootMismatch { .. })));
+        let transaction = generate_signed_transaction(&key, [1u8; 20].into(), 0, 1);
+        block.data.data.push(transaction.clone());
+        block.header.merkle_root = MerkleTree::new(&block.data.data).root();
+        assert_eq!(validate_block(&chain, &block), Err(ValidationError::DuplicateTransaction(transaction.hash())));
+        block.data.data.pop();
+        block.data.data.push(generate_signed_transaction(&key, [1u8; 20].into(), 1, 2));
+        block.header.merkle_root = MerkleTree::new(&block.data.data).root();
+        assert!(matches!(validate_block(&chain, &block), Err(ValidationError::InvalidTransaction(_, _))));
+    }
+}
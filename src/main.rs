@@ -4,23 +4,28 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod consensus;
+pub mod mempool;
 pub mod types;
 pub mod miner;
 pub mod network;
 pub mod txgenerator;
 use blockchain::Blockchain;
+use blockchain::genesis::GenesisSpec;
+use blockchain::state::LedgerModel;
 use clap::clap_app;
 use smol::channel;
 use log::{error, info};
 use api::Server as ApiServer;
-use types::hash::H256;
-use types::transaction::SignedTransaction;
+use mempool::Mempool;
+use types::address::Address;
+use types::key_pair;
+use ring::signature::KeyPair;
 use std::net;
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time;
-use std::collections::HashMap;
 
 fn main() {
     // parse command line arguments
@@ -31,16 +36,75 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg network_id: --("network-id") [INT] default_value("0") "Sets the ID of the network, peers on another network are disconnected")
+     (@arg outbound_peers: --("outbound-peers") [INT] default_value("8") "Sets the number of outgoing peers to keep, dialing addresses learned from the peers")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads mining blocks")
+     (@arg ledger: --ledger [MODEL] default_value("account") "Sets the transaction model of the chain, account or utxo")
+     (@arg genesis: --genesis [FILE] "Loads the genesis block and initial allocations from a JSON spec")
+     (@arg datadir: --datadir [DIR] "Stores the blockchain and the mempool in DIR and reloads them at startup")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
+    // parse the ledger model
+    let ledger_model = matches
+        .value_of("ledger")
+        .unwrap()
+        .parse::<LedgerModel>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ledger model: {}", e);
+            process::exit(1);
+        });
+    // load the genesis spec
+    let genesis = match matches.value_of("genesis") {
+        Some(path) => GenesisSpec::load(path).unwrap_or_else(|e| {
+            error!("Error loading genesis spec {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => GenesisSpec::default(),
+    };
+    let blockchain = match matches.value_of("datadir") {
+        Some(datadir) => Blockchain::open(Path::new(datadir), &genesis, ledger_model).unwrap_or_else(|e| {
+            error!("Error opening data directory {}: {}", datadir, e);
+            process::exit(1);
+        }),
+        None => Blockchain::from_genesis(&genesis, ledger_model),
+    };
+    info!("Genesis block {}", blockchain.genesis());
+    // reload the transactions that were pending when the node last shut down
+    let mut mem_pool = Mempool::default();
+    let mempool_file = matches.value_of("datadir").map(|datadir| Path::new(datadir).join("mempool.dat"));
+    if let Some(path) = &mempool_file {
+        match mem_pool.load(path, blockchain.state(&blockchain.tip()).unwrap()) {
+            Ok(loaded) => info!("Reloaded {} pending transactions", loaded),
+            Err(e) => error!("Error reloading mempool {}: {}", path.display(), e),
+        }
+    }
     let blockchain = Arc::new(Mutex::new(blockchain));
-    let mem_pool = Arc::new(Mutex::new(HashMap::new()));
+    // the node key receives the mining rewards and funds the generated transactions
+    let key = key_pair::random();
+    let address = Address::from_public_key_bytes(key.public_key().as_ref());
+    info!("Node address {}", address);
+    let mem_pool = Arc::new(Mutex::new(mem_pool));
+    // dump the pending transactions on shutdown
+    if let Some(path) = mempool_file {
+        let mem_pool = Arc::clone(&mem_pool);
+        ctrlc::set_handler(move || {
+            let mem_pool = mem_pool.lock().unwrap();
+            match mem_pool.dump(&path) {
+                Ok(()) => info!("Dumped {} pending transactions", mem_pool.len()),
+                Err(e) => error!("Error dumping mempool {}: {}", path.display(), e),
+            }
+            process::exit(0);
+        })
+        .unwrap_or_else(|e| {
+            error!("Error setting the shutdown handler: {}", e);
+            process::exit(1);
+        });
+    }
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -61,13 +125,51 @@ fn main() {
             process::exit(1);
         });
 
+    // parse the network ID
+    let network_id = matches
+        .value_of("network_id")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing network ID: {}", e);
+            process::exit(1);
+        });
+
+    // parse the number of outgoing peers
+    let outbound_peers = matches
+        .value_of("outbound_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx, network_id, &blockchain, outbound_peers).unwrap();
     server_ctx.start().unwrap();
 
+    // start the miner
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    if miner_threads == 0 {
+        error!("Error parsing miner threads: at least one thread is required");
+        process::exit(1);
+    }
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mem_pool, address, miner_threads);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan);
+    miner_ctx.start();
+    miner_worker_ctx.start();
+
     // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
@@ -82,20 +184,16 @@ fn main() {
         msg_rx,
         &server,
         &blockchain,
-        &mem_pool
+        &mem_pool,
+        &miner
     );
     worker_ctx.start();
 
     // start the transaction generator 
-    let (txgen_ctx, txgen, tx_channel) = txgenerator::new(&blockchain, &mem_pool);
-    let txgen_worker_ctx = txgenerator::worker::Worker::new(&server, tx_channel, &mem_pool, );
+    let (txgen_ctx, txgen, tx_channel) = txgenerator::new(&blockchain, &mem_pool, key);
+    let txgen_worker_ctx = txgenerator::worker::Worker::new(&server, tx_channel, &blockchain, &mem_pool, &miner);
     txgen_ctx.start();
     txgen_worker_ctx.start();
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mem_pool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
-    miner_ctx.start();
-    miner_worker_ctx.start();
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
@@ -103,28 +201,17 @@ fn main() {
         let server = server.clone();
         thread::spawn(move || {
             for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
+                let addr = match peer.parse::<net::SocketAddr>() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("Error parsing peer address {}: {}", &peer, e);
+                        continue;
                     }
+                };
+                // the server keeps dialing the peers it cannot reach yet, backing off
+                match server.connect(addr) {
+                    Ok(_) => info!("Connected to outgoing peer {}", &addr),
+                    Err(e) => error!("Error connecting to peer {}, retrying later: {}", addr, e),
                 }
             }
         });
@@ -0,0 +1,769 @@
+use crate::blockchain::state::{State, TransactionError};
+use crate::types::address::Address;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{Payload, SignedTransaction, Transaction};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use log::debug;
+
+/// Default limit on the total size of the pending transactions, in bytes
+pub const DEFAULT_MAX_SIZE: u64 = 16_000_000;
+/// Default time after which a pending transaction is dropped
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(60 * 60);
+/// Default fee a replacement must pay on top of the fee of the transaction it replaces
+pub const DEFAULT_REPLACEMENT_INCREMENT: u128 = 1;
+/// Largest distance between the nonce of a pending transfer and the next nonce of its sender
+pub const MAX_NONCE_GAP: u128 = 64;
+/// Largest number of transfers a sender may have in the future queue
+pub const MAX_FUTURE_PER_SENDER: usize = 16;
+
+/// Reasons for which the mempool rejects a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// the transaction is already pending
+    AlreadyKnown,
+    /// the transaction is not valid on top of the tip
+    Invalid(TransactionError),
+    /// another pending transaction, identified by its hash, has the same sender and nonce and
+    /// the replacement does not pay the required fee
+    ReplacementFeeTooLow { conflict: H256, required: u128, found: u128 },
+    /// the pool is full of transactions paying a higher fee rate
+    FeeTooLow,
+    /// the pool has no room left for a transfer following a nonce gap
+    Full,
+    /// the nonce is too far ahead of the next nonce of the sender
+    NonceTooFarAhead { expected: u128, found: u128 },
+    /// the sender already has the largest number of transfers allowed in the future queue
+    TooManyFuture,
+    /// the transaction alone is larger than the pool
+    TooLarge { size: u64, max_size: u64 },
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => write!(f, "already in the mempool"),
+            MempoolError::Invalid(e) => write!(f, "invalid transaction: {}", e),
+            MempoolError::ReplacementFeeTooLow { conflict, required, found } => {
+                write!(f, "fee {} too low to replace pending transaction {}, {} required", found, conflict, required)
+            }
+            MempoolError::FeeTooLow => write!(f, "fee rate too low for a full mempool"),
+            MempoolError::Full => write!(f, "no room in the mempool for a future transfer"),
+            MempoolError::NonceTooFarAhead { expected, found } => {
+                write!(f, "nonce {} too far ahead of the next nonce {}", found, expected)
+            }
+            MempoolError::TooManyFuture => write!(f, "too many future transfers from the sender"),
+            MempoolError::TooLarge { size, max_size } => {
+                write!(f, "size {} above the mempool limit {}", size, max_size)
+            }
+        }
+    }
+}
+
+/// Fee paid per byte, compared without dividing.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FeeRate {
+    pub fee: u128,
+    pub size: u64,
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let left = self.fee.saturating_mul(u128::from(other.size));
+        let right = other.fee.saturating_mul(u128::from(self.size));
+        left.cmp(&right)
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    transaction: SignedTransaction,
+    fee: u128,
+    size: u64,
+    added: Instant,
+}
+
+/// Pending transactions, indexed by hash, by sender and nonce, by fee rate and by arrival time.
+/// The pool is bounded in bytes, evicting the lowest fee rates first, and drops the transactions
+/// that stayed pending longer than the expiry. A transfer replaces the pending one with the same
+/// sender and nonce if it pays at least the replacement increment more. Transfers that follow a
+/// nonce gap wait in a separate future queue until the gap is filled. They are not ranked by fee
+/// rate, as they cannot be mined, and are evicted before the transactions that can.
+#[derive(Debug)]
+pub struct Mempool {
+    entries: HashMap<H256, Entry>,
+    by_nonce: HashMap<(Address, u128), H256>,
+    /// transactions that can be mined, by fee rate
+    by_fee_rate: BTreeSet<(FeeRate, H256)>,
+    by_time: BTreeSet<(Instant, H256)>,
+    /// transfers waiting for an earlier nonce of their sender, not yet minable, by arrival time
+    future: BTreeSet<(Instant, H256)>,
+    size: u64,
+    max_size: u64,
+    expiry: Duration,
+    replacement_increment: u128,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, DEFAULT_REPLACEMENT_INCREMENT)
+    }
+}
+
+impl Mempool {
+    /// Create an empty pool holding at most `max_size` bytes of transactions for at most `expiry`,
+    /// in which a replacement must pay `replacement_increment` more than the transaction it replaces
+    pub fn new(max_size: u64, expiry: Duration, replacement_increment: u128) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            by_nonce: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            by_time: BTreeSet::new(),
+            future: BTreeSet::new(),
+            size: 0,
+            max_size,
+            expiry,
+            replacement_increment,
+        }
+    }
+
+    /// Add a transaction checked against the tip state, evicting future transfers and then lower
+    /// fee rates if the pool is full. Transfers with a nonce ahead of the sender's next one are
+    /// accepted if the sender can pay for them after its pending transfers, they become valid
+    /// once the earlier transfers are mined. Returns the hash of the transaction.
+    /// A transfer with the same sender and nonce as a pending one replaces it, only if its fee is
+    /// higher by at least the replacement increment.
+    pub fn insert(&mut self, transaction: SignedTransaction, state: &State) -> Result<H256, MempoolError> {
+        self.expire();
+        let hash = transaction.hash();
+        if self.entries.contains_key(&hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        let fee = match (state.check(&transaction), &transaction.transaction) {
+            (Ok(fee), _) => fee,
+            (Err(TransactionError::InvalidNonce { expected, found }), Payload::Account(payload)) if found > expected => {
+                self.check_queued(payload, state)?
+            }
+            (Err(e), _) => return Err(MempoolError::Invalid(e)),
+        };
+        let key = sender_nonce(&transaction);
+        let replaced = key.and_then(|key| self.by_nonce.get(&key).copied());
+        if let Some(conflict) = replaced {
+            let required = self.entries[&conflict].fee.saturating_add(self.replacement_increment);
+            if fee < required {
+                return Err(MempoolError::ReplacementFeeTooLow { conflict, required, found: fee });
+            }
+        }
+        // a transfer is mined after the previous nonce of its sender, if that one can be mined
+        let future = match key {
+            Some((sender, nonce)) => {
+                let previous = self.by_nonce.get(&(sender, nonce - 1));
+                nonce != state.nonce(&sender) + 1 && previous.is_none_or(|previous| self.is_future(previous))
+            }
+            None => false,
+        };
+        if let (true, Some((sender, _))) = (future, key) {
+            let next = state.nonce(&sender) + 1;
+            let queued = (next..=next + MAX_NONCE_GAP)
+                .filter_map(|nonce| self.by_nonce.get(&(sender, nonce)))
+                .filter(|queued| Some(**queued) != replaced && self.is_future(queued))
+                .count();
+            if queued >= MAX_FUTURE_PER_SENDER {
+                return Err(MempoolError::TooManyFuture);
+            }
+        }
+        let size = transaction.size();
+        if size > self.max_size {
+            return Err(MempoolError::TooLarge { size, max_size: self.max_size });
+        }
+        // a future transfer only takes free room, others evict the future transfers first, the
+        // oldest ones first, then the lowest fee rates, all of them below the new one
+        let rate = FeeRate { fee, size };
+        let mut evicted = Vec::new();
+        let mut freed = replaced.map(|conflict| self.entries[&conflict].size).unwrap_or(0);
+        if future && self.size - freed + size > self.max_size {
+            return Err(MempoolError::Full);
+        }
+        // the earlier transfers of the sender are never evicted, the new one depends on them
+        let ancestor = |candidate: &H256| match (key, sender_nonce(&self.entries[candidate].transaction)) {
+            (Some((sender, nonce)), Some((candidate_sender, candidate_nonce))) => {
+                candidate_sender == sender && candidate_nonce < nonce
+            }
+            _ => false,
+        };
+        let mut removed = HashSet::new();
+        let queued = self.future.iter().map(|(_, hash)| (None, hash));
+        let ranked = self.by_fee_rate.iter().map(|(lowest, hash)| (Some(lowest), hash));
+        for (lowest, candidate) in queued.chain(ranked) {
+            if self.size - freed + size <= self.max_size {
+                break;
+            }
+            if Some(*candidate) == replaced || removed.contains(candidate) || ancestor(candidate) {
+                continue;
+            }
+            if lowest.is_some_and(|lowest| *lowest >= rate) {
+                return Err(MempoolError::FeeTooLow);
+            }
+            // the later transfers of the evicted sender go with it
+            for descendant in self.with_descendants(candidate) {
+                if removed.insert(descendant) {
+                    freed += self.entries[&descendant].size;
+                }
+            }
+            evicted.push(*candidate);
+        }
+        if self.size - freed + size > self.max_size {
+            return Err(MempoolError::FeeTooLow);
+        }
+        if let Some(conflict) = replaced {
+            debug!("Transaction {} replaced by {}", conflict, hash);
+            self.remove(&conflict);
+        }
+        for evicted in evicted {
+            self.evict(&evicted);
+        }
+
+        let added = Instant::now();
+        if let Some(key) = key {
+            self.by_nonce.insert(key, hash);
+        }
+        self.by_time.insert((added, hash));
+        self.size += size;
+        self.entries.insert(hash, Entry { transaction, fee, size, added });
+        if future {
+            self.future.insert((added, hash));
+        } else {
+            self.by_fee_rate.insert((rate, hash));
+            if let Some((sender, nonce)) = key {
+                self.promote(sender, nonce + 1);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Check a transfer whose nonce is ahead of the next nonce of its sender, returning its fee.
+    /// The nonce must be within `MAX_NONCE_GAP`, and the sender must be able to pay for it after
+    /// the pending transfers with lower nonces.
+    fn check_queued(&self, payload: &Transaction, state: &State) -> Result<u128, MempoolError> {
+        let (sender, found) = (payload.sender, payload.nonce);
+        let expected = state.nonce(&sender) + 1;
+        if found - expected > MAX_NONCE_GAP {
+            return Err(MempoolError::NonceTooFarAhead { expected, found });
+        }
+        let pending = (expected..found)
+            .filter_map(|nonce| self.by_nonce.get(&(sender, nonce)))
+            .fold(0u128, |pending, hash| pending.saturating_add(cost(&self.entries[hash].transaction)));
+        let balance = state.balance(&sender).saturating_sub(pending);
+        let value = payload.value.saturating_add(payload.fee);
+        if balance < value {
+            return Err(MempoolError::Invalid(TransactionError::InsufficientBalance { balance, value }));
+        }
+        Ok(payload.fee)
+    }
+
+    /// Check whether a pending transfer is in the future queue
+    fn is_future(&self, hash: &H256) -> bool {
+        self.entries.get(hash).is_some_and(|entry| self.future.contains(&(entry.added, *hash)))
+    }
+
+    /// Move a pending transaction into the future queue, or out of it into the fee rate ranking
+    fn set_future(&mut self, hash: &H256, future: bool) {
+        let entry = &self.entries[hash];
+        let rate = (FeeRate { fee: entry.fee, size: entry.size }, *hash);
+        let queued = (entry.added, *hash);
+        if future {
+            self.by_fee_rate.remove(&rate);
+            self.future.insert(queued);
+        } else {
+            self.future.remove(&queued);
+            self.by_fee_rate.insert(rate);
+        }
+    }
+
+    /// Move the transfers of a sender from the given nonce on out of the future queue, until a gap
+    fn promote(&mut self, sender: Address, mut nonce: u128) {
+        while let Some(next) = self.by_nonce.get(&(sender, nonce)).copied() {
+            if !self.is_future(&next) {
+                break;
+            }
+            self.set_future(&next, false);
+            nonce += 1;
+        }
+    }
+
+    /// Check every transaction again on top of a new tip state. Stale nonces, overdrawn transfers,
+    /// future transfers beyond the limits and double spends are dropped, and the transfers
+    /// following a nonce gap move to the future queue. Returns the number of dropped transactions.
+    pub fn revalidate(&mut self, state: &State) -> usize {
+        let mut dropped = Vec::new();
+        let mut queued = Vec::new();
+        // replay the transfers of every sender in nonce order
+        let mut senders: HashMap<Address, Vec<(u128, H256)>> = HashMap::new();
+        for ((sender, nonce), hash) in &self.by_nonce {
+            senders.entry(*sender).or_default().push((*nonce, *hash));
+        }
+        for (sender, mut transfers) in senders {
+            transfers.sort_unstable();
+            let next = state.nonce(&sender) + 1;
+            let mut expected = next;
+            let mut balance = state.balance(&sender);
+            let mut future = 0;
+            for (nonce, hash) in transfers {
+                let cost = cost(&self.entries[&hash].transaction);
+                let beyond = nonce > expected && (nonce - next > MAX_NONCE_GAP || future >= MAX_FUTURE_PER_SENDER);
+                if nonce < expected || cost > balance || beyond {
+                    dropped.push(hash);
+                    continue;
+                }
+                balance -= cost;
+                if nonce > expected {
+                    future += 1;
+                    queued.push(hash);
+                } else {
+                    expected += 1;
+                    self.set_future(&hash, false);
+                }
+            }
+        }
+        for hash in &queued {
+            self.set_future(hash, true);
+        }
+        // an output spent by several pending transactions goes to the highest fee rate
+        if let State::Utxo(utxos) = state {
+            let mut spent = HashSet::new();
+            for (_, hash) in self.by_fee_rate.iter().rev() {
+                let transaction = &self.entries[hash].transaction;
+                if let Payload::Utxo(payload) = &transaction.transaction {
+                    let conflict = payload.inputs.iter().any(|input| spent.contains(input));
+                    if conflict || utxos.check(&transaction.signer(), payload).is_err() {
+                        dropped.push(*hash);
+                    } else {
+                        spent.extend(payload.inputs.iter().copied());
+                    }
+                }
+            }
+        }
+        for hash in &dropped {
+            self.remove(hash);
+        }
+        dropped.len()
+    }
+
+    /// Remove a transaction, returning it if it was pending
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let entry = self.entries.remove(hash)?;
+        if let Some(key) = sender_nonce(&entry.transaction) {
+            self.by_nonce.remove(&key);
+        }
+        self.by_fee_rate.remove(&(FeeRate { fee: entry.fee, size: entry.size }, *hash));
+        self.by_time.remove(&(entry.added, *hash));
+        self.future.remove(&(entry.added, *hash));
+        self.size -= entry.size;
+        Some(entry.transaction)
+    }
+
+    /// Get a pending transaction and the later transfers of its sender, removed along with it by
+    /// `evict`
+    fn with_descendants(&self, hash: &H256) -> Vec<H256> {
+        let mut hashes = vec![*hash];
+        if let Some((sender, mut nonce)) = sender_nonce(&self.entries[hash].transaction) {
+            nonce += 1;
+            while let Some(next) = self.by_nonce.get(&(sender, nonce)) {
+                hashes.push(*next);
+                nonce += 1;
+            }
+        }
+        hashes
+    }
+
+    /// Remove a transaction and the later transfers of its sender, which cannot be mined without it
+    fn evict(&mut self, hash: &H256) {
+        let transaction = match self.remove(hash) {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        if let Some((sender, mut nonce)) = sender_nonce(&transaction) {
+            nonce += 1;
+            while let Some(next) = self.by_nonce.get(&(sender, nonce)).copied() {
+                self.remove(&next);
+                nonce += 1;
+            }
+        }
+    }
+
+    /// Remove the transactions confirmed in a block, and the pending ones spending the same sender
+    /// nonces
+    pub fn remove_confirmed(&mut self, transactions: &[SignedTransaction]) {
+        for transaction in transactions {
+            self.remove(&transaction.hash());
+            if let Some(conflict) = sender_nonce(transaction).and_then(|key| self.by_nonce.get(&key).copied()) {
+                self.remove(&conflict);
+            }
+        }
+    }
+
+    /// Drop the transactions pending for longer than the expiry, returning how many were dropped
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (added, hash) in &self.by_time {
+            if now.duration_since(*added) < self.expiry {
+                break;
+            }
+            expired.push(*hash);
+        }
+        for hash in &expired {
+            self.remove(hash);
+        }
+        expired.len()
+    }
+
+    /// Write every pending transaction, future ones included, to a file. The file is replaced at
+    /// once, so that an interrupted dump leaves the previous one intact.
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let transactions: Vec<&SignedTransaction> = self.entries.values().map(|entry| &entry.transaction).collect();
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        bincode::serialize_into(&mut writer, &transactions).map_err(io::Error::other)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Add the transactions dumped to a file, checking each against the tip state.
+    /// Returns the number of transactions accepted, 0 if there is no file.
+    pub fn load(&mut self, path: &Path, state: &State) -> io::Result<usize> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let transactions: Vec<SignedTransaction> = bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut accepted = 0;
+        for transaction in transactions {
+            let hash = transaction.hash();
+            match self.insert(transaction, state) {
+                Ok(_) => accepted += 1,
+                Err(e) => debug!("Dumped transaction {} dropped: {}", hash, e),
+            }
+        }
+        Ok(accepted)
+    }
+
+    /// Check whether a transaction is pending
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Get a pending transaction
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.entries.get(hash).map(|entry| &entry.transaction)
+    }
+
+    /// Iterate over the pending transactions that can be mined, in no particular order
+    pub fn transactions(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.by_fee_rate.iter().map(move |(_, hash)| &self.entries[hash].transaction)
+    }
+
+    /// Get the number of transfers in the future queue
+    pub fn future_len(&self) -> usize {
+        self.future.len()
+    }
+
+    /// Get the number of pending transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether no transaction is pending
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the total size of the pending transactions, in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Get the value and fee an account transfer takes from its sender
+fn cost(transaction: &SignedTransaction) -> u128 {
+    match &transaction.transaction {
+        Payload::Account(payload) => payload.value.saturating_add(payload.fee),
+        _ => 0,
+    }
+}
+
+/// Get the sender and nonce of an account transfer
+fn sender_nonce(transaction: &SignedTransaction) -> Option<(Address, u128)> {
+    match &transaction.transaction {
+        Payload::Account(payload) => Some((payload.sender, payload.nonce)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::state::{AccountState, generate_signed_transaction_with_fee};
+    use crate::types::key_pair;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn funded(keys: &[&Ed25519KeyPair]) -> State {
+        let mut accounts = AccountState::new();
+        for key in keys {
+            accounts.credit(&Address::from_public_key_bytes(key.public_key().as_ref()), 100);
+        }
+        State::Account(accounts)
+    }
+
+    #[test]
+    fn insert_and_reject() {
+        let key = key_pair::random();
+        let state = funded(&[&key]);
+        let receiver = [1u8; 20].into();
+        let mut mempool = Mempool::default();
+        let first = generate_signed_transaction_with_fee(&key, receiver, 1, 1, 1);
+        let hash = mempool.insert(first.clone(), &state).unwrap();
+        assert!(mempool.contains(&hash));
+        assert_eq!(mempool.size(), first.size());
+        assert_eq!(mempool.insert(first, &state), Err(MempoolError::AlreadyKnown));
+        let conflict = generate_signed_transaction_with_fee(&key, receiver, 2, 1, 1);
+        assert_eq!(
+            mempool.insert(conflict, &state),
+            Err(MempoolError::ReplacementFeeTooLow { conflict: hash, required: 2, found: 1 })
+        );
+        // a later nonce waits for the earlier transfers, a spent one never becomes valid
+        let future = generate_signed_transaction_with_fee(&key, receiver, 1, 3, 1);
+        assert!(mempool.insert(future, &state).is_ok());
+        let mut spent = state.clone();
+        spent.execute(&generate_signed_transaction_with_fee(&key, receiver, 1, 1, 0)).unwrap();
+        let stale = generate_signed_transaction_with_fee(&key, receiver, 5, 1, 0);
+        assert_eq!(
+            mempool.insert(stale, &spent),
+            Err(MempoolError::Invalid(TransactionError::InvalidNonce { expected: 2, found: 1 }))
+        );
+        assert_eq!(mempool.len(), 2);
+        mempool.remove_confirmed(&[generate_signed_transaction_with_fee(&key, receiver, 1, 1, 0)]);
+        assert_eq!(mempool.len(), 1);
+        assert!(!mempool.contains(&hash));
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let key = key_pair::random();
+        let state = funded(&[&key]);
+        let receiver = [1u8; 20].into();
+        let mut mempool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 5);
+        let original = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 1, 10), &state).unwrap();
+        let later = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 2, 10), &state).unwrap();
+        let cheap = generate_signed_transaction_with_fee(&key, receiver, 2, 1, 14);
+        assert_eq!(
+            mempool.insert(cheap, &state),
+            Err(MempoolError::ReplacementFeeTooLow { conflict: original, required: 15, found: 14 })
+        );
+        let replacement = generate_signed_transaction_with_fee(&key, receiver, 2, 1, 15);
+        let hash = mempool.insert(replacement.clone(), &state).unwrap();
+        assert!(!mempool.contains(&original));
+        assert!(mempool.contains(&hash));
+        assert!(mempool.contains(&later));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.size(), 2 * replacement.size());
+    }
+
+    #[test]
+    fn future_queue() {
+        let key = key_pair::random();
+        let state = funded(&[&key]);
+        let receiver = [1u8; 20].into();
+        let mut mempool = Mempool::default();
+        let third = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 3, 1), &state).unwrap();
+        let second = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 2, 1), &state).unwrap();
+        assert_eq!(mempool.future_len(), 2);
+        assert_eq!(mempool.transactions().count(), 0);
+        // the first nonce fills the gap
+        let first = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 1, 1), &state).unwrap();
+        assert_eq!(mempool.future_len(), 0);
+        let mut ready: Vec<H256> = mempool.transactions().map(|t| t.hash()).collect();
+        ready.sort();
+        let mut expected = vec![first, second, third];
+        expected.sort();
+        assert_eq!(ready, expected);
+    }
+
+    #[test]
+    fn revalidate_on_new_tip() {
+        let (key, other) = (key_pair::random(), key_pair::random());
+        let state = funded(&[&key, &other]);
+        let receiver = [1u8; 20].into();
+        let mut mempool = Mempool::default();
+        let stale = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 10, 1, 1), &state).unwrap();
+        let next = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 10, 2, 1), &state).unwrap();
+        let overdraft = mempool.insert(generate_signed_transaction_with_fee(&other, receiver, 50, 2, 1), &state).unwrap();
+        let gap = mempool.insert(generate_signed_transaction_with_fee(&other, receiver, 1, 3, 1), &state).unwrap();
+
+        // the new tip confirms another first transfer of each sender
+        let mut tip = state;
+        tip.execute(&generate_signed_transaction_with_fee(&key, receiver, 5, 1, 0)).unwrap();
+        tip.execute(&generate_signed_transaction_with_fee(&other, receiver, 60, 1, 0)).unwrap();
+        assert_eq!(mempool.revalidate(&tip), 2);
+        assert!(!mempool.contains(&stale));
+        assert!(!mempool.contains(&overdraft));
+        let ready: Vec<H256> = mempool.transactions().map(|t| t.hash()).collect();
+        assert_eq!(ready, vec![next]);
+        assert!(mempool.contains(&gap));
+        assert_eq!(mempool.future_len(), 1);
+    }
+
+    #[test]
+    fn bound_future_transfers() {
+        let (key, other, broke) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let state = funded(&[&key, &other]);
+        let receiver = [1u8; 20].into();
+        let mut mempool = Mempool::default();
+        // a future transfer must be paid for by the balance left after the earlier pending ones
+        let unfunded = generate_signed_transaction_with_fee(&broke, receiver, 0, 2, u128::MAX / 4);
+        assert_eq!(
+            mempool.insert(unfunded, &state),
+            Err(MempoolError::Invalid(TransactionError::InsufficientBalance { balance: 0, value: u128::MAX / 4 }))
+        );
+        mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 60, 1, 0), &state).unwrap();
+        assert_eq!(
+            mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 50, 3, 0), &state),
+            Err(MempoolError::Invalid(TransactionError::InsufficientBalance { balance: 40, value: 50 }))
+        );
+        let far = 2 + MAX_NONCE_GAP;
+        assert_eq!(
+            mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 0, far, 0), &state),
+            Err(MempoolError::NonceTooFarAhead { expected: 1, found: far })
+        );
+        for nonce in 0..MAX_FUTURE_PER_SENDER as u128 {
+            mempool.insert(generate_signed_transaction_with_fee(&other, receiver, 0, nonce + 2, 0), &state).unwrap();
+        }
+        let extra = generate_signed_transaction_with_fee(&other, receiver, 0, MAX_FUTURE_PER_SENDER as u128 + 2, 0);
+        assert_eq!(mempool.insert(extra, &state), Err(MempoolError::TooManyFuture));
+        assert_eq!(mempool.future_len(), MAX_FUTURE_PER_SENDER);
+
+        // the future transfers that the new tip leaves unfunded are dropped
+        let mut tip = state;
+        tip.execute(&generate_signed_transaction_with_fee(&other, receiver, 95, 1, 0)).unwrap();
+        let costly = generate_signed_transaction_with_fee(&key, receiver, 30, 3, 0);
+        let costly = mempool.insert(costly, &tip).unwrap();
+        assert_eq!(mempool.revalidate(&tip), 0);
+        tip.execute(&generate_signed_transaction_with_fee(&key, receiver, 80, 1, 0)).unwrap();
+        // the pending first transfer is stale, and 20 are left for the future one
+        assert_eq!(mempool.revalidate(&tip), 2);
+        assert!(!mempool.contains(&costly));
+    }
+
+    #[test]
+    fn keep_own_ancestors() {
+        let key = key_pair::random();
+        let state = funded(&[&key]);
+        let receiver = [1u8; 20].into();
+        let first = generate_signed_transaction_with_fee(&key, receiver, 1, 1, 1);
+        let mut mempool = Mempool::new(first.size(), DEFAULT_EXPIRY, DEFAULT_REPLACEMENT_INCREMENT);
+        mempool.insert(first.clone(), &state).unwrap();
+        // the second transfer cannot push out the first one it depends on
+        let second = generate_signed_transaction_with_fee(&key, receiver, 1, 2, 90);
+        assert_eq!(mempool.insert(second, &state), Err(MempoolError::FeeTooLow));
+        assert!(mempool.contains(&first.hash()));
+        assert_eq!(mempool.transactions().count(), 1);
+        assert_eq!(mempool.future_len(), 0);
+    }
+
+    #[test]
+    fn evict_future_first() {
+        let (ready, queued, late) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let state = funded(&[&ready, &queued, &late]);
+        let receiver = [1u8; 20].into();
+        let cheap = generate_signed_transaction_with_fee(&ready, receiver, 1, 1, 1);
+        let future = generate_signed_transaction_with_fee(&queued, receiver, 1, 2, 50);
+        let mut mempool = Mempool::new(2 * cheap.size(), DEFAULT_EXPIRY, DEFAULT_REPLACEMENT_INCREMENT);
+        mempool.insert(cheap.clone(), &state).unwrap();
+        mempool.insert(future.clone(), &state).unwrap();
+        // the future transfer goes first, whatever its fee, and never evicts a minable one
+        let next = generate_signed_transaction_with_fee(&late, receiver, 1, 1, 1);
+        mempool.insert(next.clone(), &state).unwrap();
+        assert!(!mempool.contains(&future.hash()));
+        assert!(mempool.contains(&cheap.hash()));
+        assert_eq!(mempool.insert(future, &state), Err(MempoolError::Full));
+        assert_eq!(mempool.transactions().count(), 2);
+    }
+
+    #[test]
+    fn dump_and_load() {
+        let key = key_pair::random();
+        let state = funded(&[&key]);
+        let receiver = [1u8; 20].into();
+        let path = std::env::temp_dir().join(format!("mempool-{}.dat", crate::types::hash::generate_random_hash()));
+        let mut mempool = Mempool::default();
+        assert_eq!(mempool.load(&path, &state).unwrap(), 0);
+        let first = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 1, 1), &state).unwrap();
+        let second = mempool.insert(generate_signed_transaction_with_fee(&key, receiver, 1, 2, 1), &state).unwrap();
+        mempool.dump(&path).unwrap();
+
+        // the first transfer was mined while the node was down
+        let mut tip = state.clone();
+        tip.execute(mempool.get(&first).unwrap()).unwrap();
+        let mut reloaded = Mempool::default();
+        assert_eq!(reloaded.load(&path, &tip).unwrap(), 1);
+        assert!(!reloaded.contains(&first));
+        assert!(reloaded.contains(&second));
+        assert_eq!(reloaded.transactions().count(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn evict_lowest_fee_rate() {
+        let (low, high) = (key_pair::random(), key_pair::random());
+        let state = funded(&[&low, &high]);
+        let receiver = [1u8; 20].into();
+        let cheap = generate_signed_transaction_with_fee(&low, receiver, 1, 1, 1);
+        let descendant = generate_signed_transaction_with_fee(&low, receiver, 1, 2, 50);
+        let expensive = generate_signed_transaction_with_fee(&high, receiver, 1, 1, 10);
+        let mut mempool = Mempool::new(2 * cheap.size(), DEFAULT_EXPIRY, DEFAULT_REPLACEMENT_INCREMENT);
+        mempool.insert(cheap.clone(), &state).unwrap();
+        mempool.insert(descendant.clone(), &state).unwrap();
+        assert_eq!(
+            mempool.insert(generate_signed_transaction_with_fee(&high, receiver, 1, 1, 0), &state),
+            Err(MempoolError::FeeTooLow)
+        );
+        // the cheap transfer goes, along with the transfer depending on it
+        mempool.insert(expensive.clone(), &state).unwrap();
+        assert!(!mempool.contains(&cheap.hash()));
+        assert!(!mempool.contains(&descendant.hash()));
+        assert!(mempool.contains(&expensive.hash()));
+        assert_eq!(mempool.size(), expensive.size());
+    }
+
+    #[test]
+    fn expire_old_transactions() {
+        let key = key_pair::random();
+        let state = funded(&[&key]);
+        let mut mempool = Mempool::new(DEFAULT_MAX_SIZE, Duration::from_secs(0), DEFAULT_REPLACEMENT_INCREMENT);
+        mempool.insert(generate_signed_transaction_with_fee(&key, [1u8; 20].into(), 1, 1, 1), &state).unwrap();
+        assert_eq!(mempool.expire(), 1);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size(), 0);
+    }
+}
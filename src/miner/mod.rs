@@ -1,65 +1,118 @@
+pub mod template;
 pub mod worker;
-extern crate rand;
-use rand::Rng;
-use log::info;
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::collections::HashMap;
+use log::{debug, error, info};
+use serde::Serialize;
+use crossbeam::channel::{select, unbounded, Receiver, Sender, TryRecvError};
 use std::ops::Deref;
 use std::time;
 use std::thread;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 use crate::blockchain::Blockchain;
+use crate::consensus;
+use crate::mempool::Mempool;
 use crate::types::block::{Block, Data, Header};
 use crate::types::hash::{H256, Hashable};
 // use crate::types::merkle::verify;
-use crate::types::transaction::{SignedTransaction, verify};
+use crate::types::transaction::{Coinbase, SignedTransaction};
+use crate::types::address::Address;
 use crate::types::merkle::{MerkleTree};
 
+/// Time after which the block in mining is rebuilt, to pick up new transactions
+const TEMPLATE_REFRESH: time::Duration = time::Duration::from_millis(500);
+/// Number of hashes a mining thread tries between two checks of the stop flag
+const HASH_BATCH: u64 = 1024;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,
     Exit,
 }
 
+#[derive(Debug, Clone, Copy)]
 enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
 }
 
+/// Counters of the miner, shared between its context and its handles
+#[derive(Debug)]
+struct Stats {
+    operating_state: OperatingState,
+    hashes: u64,
+    blocks_found: u64,
+    stale_blocks: u64,
+    /// time spent searching for nonces
+    mining_time: time::Duration,
+}
+
+/// Statistics of the miner since it was created.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    /// Paused, Run(lambda) or ShutDown
+    pub operating_state: String,
+    pub hashes: u64,
+    pub blocks_found: u64,
+    /// blocks found on a parent that was no longer the tip of the chain
+    pub stale_blocks: u64,
+    /// hashes tried per second of mining
+    pub hash_rate: f64,
+    /// mining time per block found, in milliseconds, none before the first block
+    pub average_block_time: Option<f64>,
+}
+
 pub struct Context {
     blockchain: Arc<Mutex<Blockchain>>,
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
-    mem_pool: Arc<Mutex<HashMap<H256, SignedTransaction>>>
+    mem_pool: Arc<Mutex<Mempool>>,
+    /// Address the coinbase of mined blocks pays to
+    address: Address,
+    /// Number of threads searching the nonce space
+    threads: usize,
+    stats: Arc<Mutex<Stats>>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    stats: Arc<Mutex<Stats>>,
 }
 
-pub fn new(blockchain:&Arc<Mutex<Blockchain>>, mem_pool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(blockchain:&Arc<Mutex<Blockchain>>, mem_pool: &Arc<Mutex<Mempool>>, address: Address, threads: usize) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let stats = Arc::new(Mutex::new(Stats {
+        operating_state: OperatingState::Paused,
+        hashes: 0,
+        blocks_found: 0,
+        stale_blocks: 0,
+        mining_time: time::Duration::from_secs(0),
+    }));
 
     let ctx = Context {
         blockchain: Arc::clone(blockchain),
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
-        mem_pool: Arc::clone(mem_pool)
+        mem_pool: Arc::clone(mem_pool),
+        address,
+        threads: threads.max(1),
+        stats: Arc::clone(&stats),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        stats,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -70,61 +123,93 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     // create an empty blockchain and mempool
     let blockchain = Blockchain::new();
     let arc_blockchain = Arc::new(Mutex::new(blockchain));
-    let mem_pool = Arc::new(Mutex::new(HashMap::new()));
-    new(&arc_blockchain, &mem_pool)
+    let mem_pool = Arc::new(Mutex::new(Mempool::default()));
+    new(&arc_blockchain, &mem_pool, [0u8; 20].into(), 2)
 }
 
+// the signals return false once the miner has shut down and dropped its control channel
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> bool {
+        self.control_chan.send(ControlSignal::Exit).is_ok()
     }
 
-    pub fn start(&self, lambda: u64) {
+    pub fn start(&self, lambda: u64) -> bool {
         self.control_chan
             .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .is_ok()
     }
 
-    pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+    /// Stop mining until the next `start`
+    pub fn pause(&self) -> bool {
+        self.control_chan.send(ControlSignal::Pause).is_ok()
+    }
+
+    pub fn update(&self) -> bool {
+        self.control_chan.send(ControlSignal::Update).is_ok()
+    }
+
+    /// Get the operating state and the statistics of the miner
+    pub fn status(&self) -> Status {
+        let stats = self.stats.lock().unwrap();
+        let seconds = stats.mining_time.as_secs_f64();
+        let hash_rate = if seconds > 0.0 { stats.hashes as f64 / seconds } else { 0.0 };
+        let average_block_time = if stats.blocks_found > 0 {
+            Some(seconds * 1000.0 / stats.blocks_found as f64)
+        } else {
+            None
+        };
+        Status {
+            operating_state: format!("{:?}", stats.operating_state),
+            hashes: stats.hashes,
+            blocks_found: stats.blocks_found,
+            stale_blocks: stats.stale_blocks,
+            hash_rate,
+            average_block_time,
+        }
     }
 }
 
 impl Context {
     pub fn start(mut self) {
+        let threads = self.threads;
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
-        info!("Miner initialized into paused mode");
+        info!("Miner initialized into paused mode with {} threads", threads);
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+            }
+            ControlSignal::Start(i) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                self.operating_state = OperatingState::Run(i);
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Update => {
+                // the template is rebuilt on top of the current tip and mem pool in the next round
+            }
+        }
+        self.stats.lock().unwrap().operating_state = self.operating_state;
     }
 
     fn miner_loop(&mut self) {
         // main mining loop
-
         loop {
-            let chain = self.blockchain.lock().unwrap();
-            let mut parent = chain.tip();
-            drop(chain);
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
                     let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
+                    self.handle_signal(signal);
                     continue;
                 }
                 OperatingState::ShutDown => {
@@ -132,90 +217,187 @@ impl Context {
                 }
                 _ => match self.control_chan.try_recv() {
                     Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                let chain = self.blockchain.lock().unwrap();
-                                parent = chain.tip();
-                                drop(chain);
-                            }
-                        };
+                        self.handle_signal(signal);
+                        continue;
                     }
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
 
-            // TODO for student: actual mining, create a block
-            let nonce: u32 = rand::thread_rng().gen();
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-            let mut vec: Vec<SignedTransaction> = Vec::new();
-            let mut data = Data{data: vec};
-            // REMEMBER TO CHANGE DIFFICULTY IN blockchain/mod.rs AS WELL!!
-            let difficulty: H256 = [10u8; 32].into();
-
-            let mut mem_pool = self.mem_pool.lock().unwrap();
-            // keeps track of number of transaction added to the block
-            let mut i = 0;
-            // vector of all included transactions
-            let mut included = Vec::new();
-            let mut invalid = Vec::new();
-            // verify the signature of every transaction in the mem_pool
-            // if it's valid add it to the block
-            for transaction in mem_pool.values() {
-                if verify(&transaction.transaction, &transaction.pubkey, &transaction.signature) {
-                    data.data.push(transaction.clone());
-                    included.push(transaction.hash());
-                    i = i + 1;
+            let template = self.build_template();
+            if let Some(block) = self.solve(&template) {
+                if self.insert_block(&block) {
+                    self.finished_block_chan.send(block).expect("Send finished block error");
                 }
-                // add invalid transactions to invalid vec
-                else {
-                    let hash = transaction.hash();
-                    invalid.push(hash);
-                }
-                // if block is full stop adding transactions
-                if i >= 42 {
-                    break;
+            }
+
+            if let OperatingState::Run(i) = self.operating_state {
+                if i != 0 {
+                    let interval = time::Duration::from_micros(i);
+                    thread::sleep(interval);
                 }
             }
-            // remove all invalid transactions from mem_pool
-            for transaction in invalid {
-                mem_pool.remove(&transaction);
+        }
+    }
+
+    /// Insert a mined block into the chain, so that the next template is built on top of it,
+    /// and remove its transactions from the mem pool. Returns false if the block does not pass
+    /// the consensus rules its peers check it against.
+    fn insert_block(&self, block: &Block) -> bool {
+        let mut chain = self.blockchain.lock().unwrap();
+        // the tip may have moved while the block was mined
+        let stale = chain.tip() != block.get_parent();
+        let mut stats = self.stats.lock().unwrap();
+        stats.blocks_found += 1;
+        if stale {
+            stats.stale_blocks += 1;
+        }
+        drop(stats);
+        if let Err(e) = consensus::validate_block(&chain, block) {
+            error!("Mined block {} invalid: {}", block.hash(), e);
+            return false;
+        }
+        let reorg = match chain.insert(block) {
+            Ok(reorg) => reorg,
+            Err(e) => {
+                error!("Mined block {} rejected: {}", block.hash(), e);
+                return false;
             }
+        };
+        let mut mem_pool = self.mem_pool.lock().unwrap();
+        mem_pool.remove_confirmed(&block.data.data);
+        // a block mined on a stale parent may still switch the chain to its branch,
+        // put the transactions of disconnected blocks back into the mem pool
+        if let Some(reorg) = reorg {
+            let state = chain.state(&chain.tip()).unwrap();
+            for transaction in chain.orphaned_transactions(&reorg) {
+                let _ = mem_pool.insert(transaction, state);
+            }
+            // check the pending transactions against the new tip
+            let dropped = mem_pool.revalidate(state);
+            debug!("Dropped {} pending transactions invalid on the new tip", dropped);
+        }
+        true
+    }
 
-            let merkle_tree = MerkleTree::new(&data.data);
-            let merkle_root = merkle_tree.root();
-            let header = Header{parent: parent, nonce, difficulty, timestamp, merkle_root};
-            let block = Block{header, data: data};
-
-            if block.hash() <= difficulty {
-                // drop(chain);
-                self.finished_block_chan.send(block.clone()).expect("Send finished block error");
-                parent = block.hash();
-                // remove all included transactions from the mempool
-                for hash in included {
-                    mem_pool.remove(&hash);
-                }
+    /// Build a block on top of the tip, holding the transactions paying the highest fees.
+    /// The nonce of the block is left to find.
+    fn build_template(&self) -> Block {
+        // mine at the target the chain requires for a child of the tip
+        let chain = self.blockchain.lock().unwrap();
+        let parent = chain.tip();
+        // the timestamp must be above the median of the past blocks, which peers may have
+        // stamped ahead of our clock
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let timestamp = now.max(consensus::median_time_past(&chain, &parent) + 1);
+        let difficulty: H256 = chain.next_difficulty(&parent);
+        let height = chain.height(&parent).unwrap() + 1;
+        let reward = chain.params().block_reward(height);
+        let max_block_size = chain.params().max_block_size;
+        let max_block_transactions = chain.params().max_block_transactions;
+        // execute transactions on a copy of the parent's state, so that the block only
+        // contains transactions that are valid on top of the chain it extends
+        let mut state = chain.state(&parent).unwrap().clone();
+        drop(chain);
+
+        // the coinbase comes first and pays the reward and the fees of the block to the miner
+        let coinbase = Coinbase { receiver: self.address, value: reward, height };
+        let header = Header{parent, nonce: 0, difficulty, timestamp, merkle_root: [0u8; 32].into()};
+        let mut block = Block{header, data: Data{data: vec![SignedTransaction::coinbase(coinbase)]}};
+        // the transactions fill what the header and the coinbase leave of the size limit
+        let max_size = max_block_size.saturating_sub(bincode::serialized_size(&block).unwrap());
+        let max_count = max_block_transactions.saturating_sub(1);
+
+        // select from a copy of the candidates, so that the mem pool is not held while building
+        let candidates = self.mem_pool.lock().unwrap().transactions().cloned().collect();
+        let template = template::build(&mut state, candidates, max_size, max_count);
+        // remove the transactions that can never be valid from mem_pool
+        if !template.invalid.is_empty() {
+            let mut mem_pool = self.mem_pool.lock().unwrap();
+            for transaction in &template.invalid {
+                mem_pool.remove(transaction);
             }
-            // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+        }
+        let coinbase = Coinbase { receiver: self.address, value: reward.saturating_add(template.fees), height };
+        block.data.data[0] = SignedTransaction::coinbase(coinbase);
+        block.data.data.extend(template.transactions);
+        block.header.merkle_root = MerkleTree::new(&block.data.data).root();
+        block
+    }
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+    /// Search for a nonce meeting the target of a template, splitting the nonce space into one
+    /// range per thread. Gives up when a control signal arrives, such as an update of the tip or
+    /// of the mem pool, or after `TEMPLATE_REFRESH`. Returns the block found, if any.
+    fn solve(&mut self, template: &Block) -> Option<Block> {
+        let stop = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let (found_sender, found_receiver) = unbounded();
+        let threads = self.threads as u64;
+        let range = (u64::from(u32::MAX) + 1) / threads;
+        let started = Instant::now();
+        let mut solution = None;
+        let mut signal = None;
+        crossbeam::scope(|scope| {
+            for i in 0..threads {
+                let start = i * range;
+                // the last range also takes the nonces left over by the division
+                let end = if i + 1 == threads { u64::from(u32::MAX) + 1 } else { start + range };
+                let header = template.header.clone();
+                let (stop, hashes, found_sender) = (&stop, &hashes, found_sender.clone());
+                scope.spawn(move |_| search(header, start..end, stop, hashes, found_sender));
+            }
+            select! {
+                recv(found_receiver) -> header => solution = header.ok(),
+                recv(self.control_chan) -> received => signal = received.ok(),
+                default(TEMPLATE_REFRESH) => {}
+            }
+            stop.store(true, Ordering::Relaxed);
+        })
+        .unwrap();
+        let hashes = hashes.load(Ordering::Relaxed);
+        let elapsed = started.elapsed();
+        debug!("Tried {} hashes at {:.0} H/s", hashes, hashes as f64 / elapsed.as_secs_f64());
+        let mut stats = self.stats.lock().unwrap();
+        stats.hashes += hashes;
+        stats.mining_time += elapsed;
+        drop(stats);
+        if let Some(signal) = signal {
+            self.handle_signal(signal);
+            // handle the signals queued meanwhile too, so that a burst of updates rebuilds the
+            // template once
+            while let Ok(signal) = self.control_chan.try_recv() {
+                self.handle_signal(signal);
+            }
+        }
+        solution.map(|header| Block { header, data: template.data.clone() })
+    }
+}
+
+/// Try the nonces of a range on a header until one meets its target or `stop` is set. The
+/// timestamp is rolled forward every time the range is exhausted, giving the range a new hash space.
+fn search(mut header: Header, nonces: std::ops::Range<u64>, stop: &AtomicBool, hashes: &AtomicU64, found: Sender<Header>) {
+    loop {
+        let mut tried = 0;
+        for nonce in nonces.clone() {
+            header.nonce = nonce as u32;
+            if header.hash() <= header.difficulty {
+                hashes.fetch_add(tried + 1, Ordering::Relaxed);
+                let _ = found.send(header);
+                return;
+            }
+            tried += 1;
+            if tried == HASH_BATCH {
+                hashes.fetch_add(tried, Ordering::Relaxed);
+                tried = 0;
+                if stop.load(Ordering::Relaxed) {
+                    return;
                 }
             }
         }
+        hashes.fetch_add(tried, Ordering::Relaxed);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        header.timestamp = now.max(header.timestamp + 1);
     }
 }
 
@@ -241,6 +423,65 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn solve_template() {
+        let (mut miner_ctx, miner_handle, _finished_block_chan) = super::test_new();
+        let template = miner_ctx.build_template();
+        assert_eq!(template.data.data.len(), 1);
+        let block = miner_ctx.solve(&template).unwrap();
+        assert!(block.hash() <= block.get_difficulty());
+        assert_eq!(block.get_parent(), template.get_parent());
+        assert_eq!(block.header.merkle_root, template.header.merkle_root);
+        let status = miner_handle.status();
+        assert_eq!(status.operating_state, "Paused");
+        assert!(status.hashes > 0);
+        assert!(status.hash_rate > 0.0);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn timestamp_above_median() {
+        use crate::types::block::generate_random_block;
+
+        let (mut miner_ctx, _miner_handle, _finished_block_chan) = super::test_new();
+        // peers stamped the last blocks an hour ahead of our clock
+        let ahead = super::SystemTime::now().duration_since(super::UNIX_EPOCH).unwrap().as_millis() + 60 * 60 * 1000;
+        let mut chain = miner_ctx.blockchain.lock().unwrap();
+        for _ in 0..3 {
+            let mut block = generate_random_block(&chain.tip());
+            block.header.timestamp = ahead;
+            chain.insert(&block).unwrap();
+        }
+        drop(chain);
+        let template = miner_ctx.build_template();
+        assert_eq!(template.header.timestamp, ahead + 1);
+        let block = miner_ctx.solve(&template).unwrap();
+        assert!(miner_ctx.insert_block(&block));
+        assert_eq!(miner_ctx.blockchain.lock().unwrap().tip(), block.hash());
+        // a block breaking the consensus rules is not inserted
+        let mut invalid = miner_ctx.solve(&miner_ctx.build_template()).unwrap();
+        invalid.header.timestamp = 0;
+        assert!(!miner_ctx.insert_block(&invalid));
+        assert_eq!(miner_ctx.blockchain.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn pause_and_exit() {
+        let (miner_ctx, miner_handle, _finished_block_chan) = super::test_new();
+        miner_ctx.start();
+        assert!(miner_handle.start(0));
+        assert!(miner_handle.pause());
+        assert!(miner_handle.exit());
+        // the miner drops its control channel once it has shut down
+        while miner_handle.update() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(miner_handle.status().operating_state, "ShutDown");
+        assert!(!miner_handle.start(0));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
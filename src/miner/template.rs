@@ -0,0 +1,182 @@
+use crate::blockchain::state::State;
+use crate::mempool::FeeRate;
+use crate::types::address::Address;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{Payload, SignedTransaction};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+/// Transactions selected for a block, with the fees they pay.
+#[derive(Debug, Default)]
+pub struct Template {
+    pub transactions: Vec<SignedTransaction>,
+    pub fees: u128,
+    /// hashes of the candidates that can never be valid
+    pub invalid: Vec<H256>,
+}
+
+struct Entry {
+    transaction: SignedTransaction,
+    fee: u128,
+    size: u64,
+}
+
+/// Select the transactions of a block from the candidates, executing them on `state`, so as to
+/// collect the highest fees within `max_size` bytes and `max_count` transactions.
+///
+/// The transfers of a sender are queued in nonce order, and every prefix of a queue is ranked by
+/// its total fee rate: a low-fee transaction is mined ahead of the high-fee ones that depend on it.
+/// A UTXO transaction is ranked alone, by the fee it pays on top of `state`.
+pub fn build(state: &mut State, candidates: Vec<SignedTransaction>, max_size: u64, max_count: usize) -> Template {
+    let mut template = Template::default();
+    let mut senders: HashMap<Address, Vec<Entry>> = HashMap::new();
+    let mut queues: Vec<VecDeque<Entry>> = Vec::new();
+    for transaction in candidates {
+        let size = transaction.size();
+        match &transaction.transaction {
+            Payload::Account(payload) => {
+                let (sender, fee) = (payload.sender, payload.fee);
+                senders.entry(sender).or_default().push(Entry { transaction, fee, size });
+            }
+            _ => match state.check(&transaction) {
+                Ok(fee) => queues.push(vec![Entry { transaction, fee, size }].into()),
+                Err(e) if e.is_permanent() => template.invalid.push(transaction.hash()),
+                Err(_) => {}
+            },
+        }
+    }
+    for (_, mut entries) in senders {
+        entries.sort_by_key(|entry| nonce(&entry.transaction));
+        queues.push(entries.into());
+    }
+
+    // the best prefix of every queue, only the queue a prefix is taken from is ranked again
+    let mut best: BinaryHeap<(FeeRate, usize, usize)> = queues
+        .iter()
+        .enumerate()
+        .filter_map(|(index, queue)| best_prefix(queue).map(|(rate, length)| (rate, index, length)))
+        .collect();
+    let smallest = queues.iter().flatten().map(|entry| entry.size).min().unwrap_or(0);
+    let mut size = 0;
+    while let Some((_, index, length)) = best.pop() {
+        if template.transactions.len() >= max_count || max_size - size < smallest {
+            break;
+        }
+        let queue = &mut queues[index];
+        for _ in 0..length {
+            let entry = queue.pop_front().unwrap();
+            if size + entry.size > max_size || template.transactions.len() >= max_count {
+                // the later transactions of the sender depend on this one
+                queue.clear();
+                break;
+            }
+            match state.execute(&entry.transaction) {
+                Ok(fee) => {
+                    size += entry.size;
+                    template.fees = template.fees.saturating_add(fee);
+                    template.transactions.push(entry.transaction);
+                }
+                Err(e) if e.is_permanent() => template.invalid.push(entry.transaction.hash()),
+                Err(_) => {}
+            }
+        }
+        if let Some((rate, length)) = best_prefix(queue) {
+            best.push((rate, index, length));
+        }
+    }
+    template
+}
+
+/// Find the prefix of a queue with the highest fee rate, as its fee rate and its length
+fn best_prefix(queue: &VecDeque<Entry>) -> Option<(FeeRate, usize)> {
+    let mut best: Option<(FeeRate, usize)> = None;
+    let mut prefix = FeeRate { fee: 0, size: 0 };
+    for (length, entry) in queue.iter().enumerate() {
+        prefix.fee = prefix.fee.saturating_add(entry.fee);
+        prefix.size += entry.size;
+        if best.is_none_or(|(rate, _)| prefix > rate) {
+            best = Some((prefix, length + 1));
+        }
+    }
+    best
+}
+
+fn nonce(transaction: &SignedTransaction) -> u128 {
+    match &transaction.transaction {
+        Payload::Account(payload) => payload.nonce,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::state::{AccountState, generate_signed_transaction_with_fee};
+    use crate::types::key_pair;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn highest_fee_rate_first() {
+        let rich = key_pair::random();
+        let poor = key_pair::random();
+        let mut accounts = AccountState::new();
+        accounts.credit(&Address::from_public_key_bytes(rich.public_key().as_ref()), 100);
+        accounts.credit(&Address::from_public_key_bytes(poor.public_key().as_ref()), 100);
+        let mut state = State::Account(accounts);
+        let receiver = [1u8; 20].into();
+        // the poor sender's cheap first transfer is needed by its expensive second one
+        let cheap = generate_signed_transaction_with_fee(&poor, receiver, 0, 1, 1);
+        let expensive = generate_signed_transaction_with_fee(&poor, receiver, 0, 2, 20);
+        let medium = generate_signed_transaction_with_fee(&rich, receiver, 0, 1, 5);
+        let candidates = vec![expensive.clone(), medium.clone(), cheap.clone()];
+        let template = build(&mut state.clone(), candidates.clone(), u64::MAX, usize::MAX);
+        let hashes: Vec<H256> = template.transactions.iter().map(|t| t.hash()).collect();
+        assert_eq!(hashes, vec![cheap.hash(), expensive.hash(), medium.hash()]);
+        assert_eq!(template.fees, 26);
+
+        // with room for two transactions only, the pair pays more than the medium one
+        let size = cheap.size();
+        let template = build(&mut state, candidates, 2 * size, usize::MAX);
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.fees, 21);
+    }
+
+    #[test]
+    fn skip_invalid() {
+        let key = key_pair::random();
+        let mut accounts = AccountState::new();
+        accounts.credit(&Address::from_public_key_bytes(key.public_key().as_ref()), 10);
+        let mut state = State::Account(accounts);
+        let receiver = [1u8; 20].into();
+        let first = generate_signed_transaction_with_fee(&key, receiver, 5, 1, 1);
+        let overdraft = generate_signed_transaction_with_fee(&key, receiver, 5, 2, 1);
+        let mut forged = generate_signed_transaction_with_fee(&key, receiver, 1, 3, 1);
+        forged.signature[0] ^= 1;
+        let template = build(&mut state, vec![forged.clone(), overdraft, first.clone()], u64::MAX, usize::MAX);
+        let hashes: Vec<H256> = template.transactions.iter().map(|t| t.hash()).collect();
+        assert_eq!(hashes, vec![first.hash()]);
+        assert_eq!(template.invalid, vec![forged.hash()]);
+        assert_eq!(state.balance(&Address::from_public_key_bytes(key.public_key().as_ref())), 4);
+    }
+
+    #[test]
+    fn stop_when_full() {
+        let keys: Vec<_> = (0..200).map(|_| key_pair::random()).collect();
+        let mut accounts = AccountState::new();
+        for key in &keys {
+            accounts.credit(&Address::from_public_key_bytes(key.public_key().as_ref()), 1000);
+        }
+        let mut state = State::Account(accounts);
+        let receiver = [1u8; 20].into();
+        let candidates: Vec<SignedTransaction> = keys
+            .iter()
+            .enumerate()
+            .map(|(fee, key)| generate_signed_transaction_with_fee(key, receiver, 1, 1, fee as u128))
+            .collect();
+        let template = build(&mut state, candidates, u64::MAX, 10);
+        let fees: Vec<u128> = template.transactions.iter().map(|t| match &t.transaction {
+            Payload::Account(payload) => payload.fee,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(fees, (190..200).rev().collect::<Vec<u128>>());
+    }
+}
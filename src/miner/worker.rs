@@ -1,7 +1,5 @@
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
-use std::sync::{Arc, Mutex};
-use crate::blockchain::Blockchain;
+use crossbeam::channel::Receiver;
+use log::info;
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use crate::network::message::Message;
@@ -10,7 +8,6 @@ use std::thread;
 
 #[derive(Clone)]
 pub struct Worker {
-    blockchain: Arc<Mutex<Blockchain>>,
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
 }
@@ -19,10 +16,8 @@ impl Worker {
     pub fn new(
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>,
     ) -> Self {
         Self {
-            blockchain: Arc::clone(&blockchain),
             server: server.clone(),
             finished_block_chan,
         }
@@ -40,16 +35,16 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            let _block = self.finished_block_chan.recv().expect("Receive finished block error");
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash
-            // get the lock and add the finihed block to the chain
-            let mut chain = self.blockchain.lock().unwrap();
-            chain.insert(&_block);
+            // the miner has already inserted the finished block into the chain
+            let block = match self.finished_block_chan.recv() {
+                Ok(block) => block,
+                Err(_) => {
+                    info!("Miner worker shutting down");
+                    return;
+                }
+            };
             // broadcast the hash of the new block
-            let mut hash = Vec::new();
-            hash.push(_block.hash());
-            self.server.broadcast(Message::NewBlockHashes(hash));
-            drop(chain);
-        }   
+            self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+        }
     }
-}
\ No newline at end of file
+}
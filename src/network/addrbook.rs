@@ -0,0 +1,156 @@
+use super::message::PeerAddr;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest number of addresses in the book, the least recently seen ones are forgotten first
+pub const MAX_ADDRESSES: usize = 1000;
+/// Time after which an address that was not seen is forgotten, in milliseconds
+const MAX_AGE: u128 = 3 * 60 * 60 * 1000;
+/// Time before an address is dialed again
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Entry {
+    /// milliseconds since the epoch
+    last_seen: u128,
+    last_attempt: Option<Instant>,
+}
+
+/// Listening addresses of the peers the node has heard of, learned from the peers it connects
+/// to and from the addresses they gossip.
+#[derive(Default)]
+pub struct AddrBook {
+    entries: HashMap<SocketAddr, Entry>,
+}
+
+impl AddrBook {
+    /// Create an empty address book
+    pub fn new() -> Self {
+        AddrBook { entries: HashMap::new() }
+    }
+
+    /// Record that the peer listening at an address was seen at the given time, in milliseconds
+    /// since the epoch
+    pub fn add(&mut self, addr: SocketAddr, last_seen: u128) {
+        if addr.port() == 0 || addr.ip().is_unspecified() {
+            return;
+        }
+        let now = now();
+        // a gossiped timestamp cannot be in the future
+        let last_seen = last_seen.min(now);
+        if now - last_seen > MAX_AGE {
+            return;
+        }
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return;
+        }
+        if self.entries.len() >= MAX_ADDRESSES {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.last_seen).map(|(addr, _)| *addr);
+            self.entries.remove(&oldest.unwrap());
+        }
+        self.entries.insert(addr, Entry { last_seen, last_attempt: None });
+    }
+
+    /// Record that the peer listening at an address is seen now
+    pub fn seen(&mut self, addr: SocketAddr) {
+        self.add(addr, now());
+    }
+
+    /// Forget an address, such as our own
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.entries.remove(addr);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get up to `max` of the most recently seen addresses, to gossip to a peer
+    pub fn recent(&self, max: usize) -> Vec<PeerAddr> {
+        let now = now();
+        let mut addrs: Vec<PeerAddr> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now.saturating_sub(entry.last_seen) <= MAX_AGE)
+            .map(|(addr, entry)| PeerAddr { addr: *addr, last_seen: entry.last_seen })
+            .collect();
+        addrs.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        addrs.truncate(max);
+        addrs
+    }
+
+    /// Pick up to `max` random addresses to dial, leaving out those for which `skip` is true and
+    /// those dialed in the last `RETRY_INTERVAL`. The picked addresses are marked as dialed.
+    pub fn select<F: Fn(&SocketAddr) -> bool>(&mut self, max: usize, skip: F) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = self
+            .entries
+            .iter()
+            .filter(|(addr, entry)| {
+                !skip(addr) && entry.last_attempt.is_none_or(|attempt| attempt.elapsed() >= RETRY_INTERVAL)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.truncate(max);
+        for addr in &candidates {
+            self.entries.get_mut(addr).unwrap().last_attempt = Some(Instant::now());
+        }
+        candidates
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn gossip_recent_addresses() {
+        let mut book = AddrBook::new();
+        let now = now();
+        book.add(addr(1), now - 2000);
+        book.add(addr(2), now - 1000);
+        book.add(addr(1), now - 3000);
+        // too old, unroutable, and from the future
+        book.add(addr(3), now - MAX_AGE - 1000);
+        book.add(([0, 0, 0, 0], 4).into(), now);
+        book.add(addr(5), now + 60_000);
+        assert_eq!(book.len(), 3);
+        let recent = book.recent(2);
+        assert_eq!(recent.iter().map(|peer| peer.addr).collect::<Vec<_>>(), vec![addr(5), addr(2)]);
+        assert!(recent[0].last_seen <= super::now());
+        assert_eq!(book.recent(10)[2], PeerAddr { addr: addr(1), last_seen: now - 2000 });
+    }
+
+    #[test]
+    fn select_and_evict() {
+        let mut book = AddrBook::new();
+        let now = now();
+        for port in 1..=MAX_ADDRESSES as u16 {
+            book.add(addr(port), now - 10_000 + u128::from(port));
+        }
+        book.add(addr(0xffff), now);
+        assert_eq!(book.len(), MAX_ADDRESSES);
+        // the least recently seen address was forgotten
+        let selected = book.select(MAX_ADDRESSES, |candidate| candidate.port() % 2 == 0);
+        assert_eq!(selected.len(), MAX_ADDRESSES / 2);
+        assert!(!selected.contains(&addr(1)));
+        assert!(selected.iter().all(|selected| selected.port() % 2 == 1));
+        // dialed addresses are not picked again right away
+        assert_eq!(book.select(MAX_ADDRESSES, |_| false).len(), MAX_ADDRESSES / 2);
+        assert!(book.select(MAX_ADDRESSES, |_| false).is_empty());
+    }
+}
@@ -1,15 +1,155 @@
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 
 use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use super::addrbook::MAX_ADDRESSES;
+
+/// Version of the protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Largest frame accepted from a peer, whatever the message
+pub const MAX_FRAME_SIZE: u64 = 32 * 1024 * 1024;
+/// Size of the header of a message holding a vector: the variant index, then the vector length
+pub const HEADER_SIZE: u64 = 4 + 8;
+/// Largest number of hashes in a message announcing or requesting blocks or transactions
+pub const MAX_INVENTORY: u64 = 50_000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
-    Ping(String),
-    Pong(String),
+    Ping(u64),
+    Pong(u64),
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Version(Version),
+    Verack,
+    GetAddr,
+    Addr(Vec<PeerAddr>),
+}
+
+impl Message {
+    /// Get the largest serialized size of a message from its variant index, the `u32` bincode
+    /// writes first. Returns none for an unknown variant.
+    pub fn max_size(tag: u32) -> Option<u64> {
+        match tag {
+            // Ping, Pong
+            0 | 1 => Some(HEADER_SIZE),
+            // NewBlockHashes, GetBlocks, NewTransactionHashes, GetTransactions
+            2 | 3 | 5 | 6 => Some(HEADER_SIZE + 32 * MAX_INVENTORY),
+            // Blocks, Transactions
+            4 | 7 => Some(MAX_FRAME_SIZE),
+            // Version, Verack, GetAddr
+            8..=10 => Some(1024),
+            // Addr, a socket address and a timestamp per peer
+            11 => Some(HEADER_SIZE + 64 * MAX_ADDRESSES as u64),
+            _ => None,
+        }
+    }
+
+    /// Decode a message received from a peer, reading at most `MAX_FRAME_SIZE` bytes
+    pub fn decode(bytes: &[u8]) -> bincode::Result<Message> {
+        bincode::config().limit(MAX_FRAME_SIZE).deserialize(bytes)
+    }
+}
+
+/// First message sent on a new connection, describing the protocol and the chain of the node.
+/// A peer acknowledges it with `Verack` if it is on the same protocol and chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub version: u32,
+    /// ID of the network, telling apart networks started from the same genesis spec
+    pub network_id: u32,
+    pub genesis: H256,
+    /// height of the tip of the longest chain
+    pub best_height: u128,
+    /// port the node accepts connections on, at the IP address the connection comes from
+    pub listen_port: u16,
+    /// random number identifying the node, to detect connections to itself
+    pub nonce: u64,
+}
+
+/// Listening address of a peer, gossiped with the time it was last seen in milliseconds
+/// since the epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr {
+    pub addr: SocketAddr,
+    pub last_seen: u128,
+}
+
+/// Reasons for which the version of a peer is not compatible with ours.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionMismatch {
+    Protocol { expected: u32, found: u32 },
+    Network { expected: u32, found: u32 },
+    Genesis { expected: H256, found: H256 },
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VersionMismatch::Protocol { expected, found } => {
+                write!(f, "protocol version {}, expected {}", found, expected)
+            }
+            VersionMismatch::Network { expected, found } => {
+                write!(f, "network {}, expected {}", found, expected)
+            }
+            VersionMismatch::Genesis { expected, found } => {
+                write!(f, "genesis block {}, expected {}", found, expected)
+            }
+        }
+    }
+}
+
+impl Version {
+    /// Check that a peer announcing the given version is on the same protocol and chain
+    pub fn check(&self, peer: &Version) -> Result<(), VersionMismatch> {
+        if peer.version != self.version {
+            return Err(VersionMismatch::Protocol { expected: self.version, found: peer.version });
+        }
+        if peer.network_id != self.network_id {
+            return Err(VersionMismatch::Network { expected: self.network_id, found: peer.network_id });
+        }
+        if peer.genesis != self.genesis {
+            return Err(VersionMismatch::Genesis { expected: self.genesis, found: peer.genesis });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn size_limits() {
+        let peer = PeerAddr { addr: "[::1]:6000".parse().unwrap(), last_seen: u128::MAX };
+        let version = Version { version: PROTOCOL_VERSION, network_id: 0, genesis: [0u8; 32].into(), best_height: 0, listen_port: 0, nonce: 0 };
+        let hashes = vec![H256::from([0u8; 32]); MAX_INVENTORY as usize];
+        let messages = vec![
+            Message::Ping(u64::MAX),
+            Message::Pong(u64::MAX),
+            Message::NewBlockHashes(hashes.clone()),
+            Message::GetBlocks(hashes.clone()),
+            Message::Blocks(vec![generate_random_block(&[0u8; 32].into())]),
+            Message::NewTransactionHashes(hashes.clone()),
+            Message::GetTransactions(hashes),
+            Message::Transactions(vec![]),
+            Message::Version(version),
+            Message::Verack,
+            Message::GetAddr,
+            Message::Addr(vec![peer; MAX_ADDRESSES]),
+        ];
+        // the variant indices match the table of sizes, and the largest messages fit
+        for (tag, msg) in messages.iter().enumerate() {
+            let bytes = bincode::serialize(msg).unwrap();
+            assert_eq!(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), tag as u32);
+            assert!(bytes.len() as u64 <= Message::max_size(tag as u32).unwrap(), "{:?}", tag);
+            assert!(Message::decode(&bytes).is_ok());
+        }
+        assert_eq!(Message::max_size(messages.len() as u32), None);
+        assert!(Message::decode(&[1, 0, 0, 0]).is_err());
+    }
 }
@@ -1,4 +1,6 @@
+pub mod addrbook;
 pub mod message;
+pub mod orphans;
 pub mod peer;
 pub mod server;
 pub mod worker;
@@ -0,0 +1,145 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Largest number of orphan blocks kept, the oldest ones are forgotten first
+pub const MAX_ORPHANS: usize = 256;
+/// Largest number of orphan blocks kept from a single peer
+pub const MAX_ORPHANS_PER_PEER: usize = 32;
+
+struct Orphan {
+    block: Block,
+    source: SocketAddr,
+    /// arrival order
+    sequence: u64,
+}
+
+/// Blocks received before their parent, kept with the peer they came from until the parent is
+/// inserted into the chain. The pool is bounded in total and per peer: a peer at its limit has
+/// its new orphans refused, so that the first ones it sent, the highest of a branch, are kept.
+#[derive(Default)]
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    by_parent: HashMap<H256, Vec<H256>>,
+    sequence: u64,
+}
+
+impl OrphanPool {
+    /// Create an empty pool
+    pub fn new() -> Self {
+        OrphanPool::default()
+    }
+
+    /// Keep an orphan received from a peer, forgetting the oldest orphan overall if the pool is
+    /// full. Returns false if the orphan was already kept, or if the peer reached its limit.
+    pub fn insert(&mut self, block: Block, source: SocketAddr) -> bool {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return false;
+        }
+        let from_source = self.orphans.values().filter(|orphan| orphan.source == source).count();
+        if from_source >= MAX_ORPHANS_PER_PEER {
+            return false;
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            self.remove_oldest();
+        }
+        self.by_parent.entry(block.get_parent()).or_default().push(hash);
+        self.sequence += 1;
+        self.orphans.insert(hash, Orphan { block, source, sequence: self.sequence });
+        true
+    }
+
+    /// Remove the orphans whose parent is the given block, with the peers they came from
+    pub fn take_children(&mut self, parent: &H256) -> Vec<(Block, SocketAddr)> {
+        self.by_parent
+            .remove(parent)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .map(|orphan| (orphan.block, orphan.source))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .orphans
+            .iter()
+            .min_by_key(|(_, orphan)| orphan.sequence)
+            .map(|(hash, _)| *hash);
+        let orphan = match oldest.and_then(|hash| self.orphans.remove(&hash)) {
+            Some(orphan) => orphan,
+            None => return,
+        };
+        let parent = orphan.block.get_parent();
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|sibling| *sibling != orphan.block.hash());
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    fn peer(port: u16) -> SocketAddr {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn take_children() {
+        let mut pool = OrphanPool::new();
+        let parent: H256 = [7u8; 32].into();
+        let first = generate_random_block(&parent);
+        let second = generate_random_block(&parent);
+        let other = generate_random_block(&[8u8; 32].into());
+        assert!(pool.insert(first.clone(), peer(1)));
+        assert!(!pool.insert(first.clone(), peer(2)));
+        assert!(pool.insert(second.clone(), peer(2)));
+        assert!(pool.insert(other, peer(1)));
+        let mut children: Vec<(H256, SocketAddr)> =
+            pool.take_children(&parent).iter().map(|(block, source)| (block.hash(), *source)).collect();
+        children.sort_by_key(|(_, source)| source.port());
+        assert_eq!(children, vec![(first.hash(), peer(1)), (second.hash(), peer(2))]);
+        assert!(pool.take_children(&parent).is_empty());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn bound_per_peer_and_in_total() {
+        let mut pool = OrphanPool::new();
+        let parent: H256 = [7u8; 32].into();
+        let first = generate_random_block(&parent);
+        pool.insert(first.clone(), peer(1));
+        for _ in 1..MAX_ORPHANS_PER_PEER {
+            assert!(pool.insert(generate_random_block(&parent), peer(1)));
+        }
+        // a peer at its limit has its new orphans refused, its first ones are kept
+        assert!(!pool.insert(generate_random_block(&parent), peer(1)));
+        assert!(pool.insert(generate_random_block(&parent), peer(2)));
+        assert_eq!(pool.len(), MAX_ORPHANS_PER_PEER + 1);
+        assert!(pool.take_children(&parent).iter().any(|(block, _)| block.hash() == first.hash()));
+
+        let oldest = generate_random_block(&parent);
+        pool.insert(oldest.clone(), peer(0));
+        for port in 1..=MAX_ORPHANS as u16 {
+            pool.insert(generate_random_block(&parent), peer(port));
+        }
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(pool.take_children(&parent).iter().all(|(block, _)| block.hash() != oldest.hash()));
+        assert!(pool.is_empty());
+    }
+}
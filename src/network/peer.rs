@@ -5,17 +5,21 @@ use smol::Async;
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
+    direction: Direction,
+    listen_addr: std::net::SocketAddr,
 ) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        listen_addr,
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -24,6 +28,9 @@ pub enum Direction {
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction,
+    /// address the peer accepts connections on, announced in its version message
+    listen_addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
 }
 
@@ -42,15 +49,31 @@ impl Handle {
         });
     }
 
+    /// Close the connection to the peer, once the messages already queued are written
+    pub fn disconnect(&self) {
+        self.write_queue.close_channel();
+    }
+
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn listen_addr(&self) -> &std::net::SocketAddr {
+        &self.listen_addr
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
+        let addr = std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321);
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
+            direction: Direction::Incoming,
+            listen_addr: addr,
             write_queue: s,
         },
         TestReceiver {
@@ -66,4 +89,9 @@ impl TestReceiver {
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
+
+    /// Check that the peer was disconnected, after the messages written to it
+    pub fn disconnected(&mut self) -> bool {
+        smol::block_on(futures::stream::StreamExt::next(&mut self.r)).is_none()
+    }
 }
\ No newline at end of file
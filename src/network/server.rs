@@ -1,32 +1,69 @@
-use crate::types::address::Address;
+use crate::blockchain::Blockchain;
+use crate::types::hash::H256;
+use super::addrbook::AddrBook;
 use super::peer;
-use super::message;
+use super::message::{self, Message, PeerAddr, Version, PROTOCOL_VERSION};
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
-use smol::{Async, Executor};
+use smol::{Async, Executor, Timer};
 use log::{debug, info, trace};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// Time a new peer has to complete the version handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time between two checks of the number of outgoing peers
+const CONNECTION_INTERVAL: Duration = Duration::from_secs(5);
+/// Time between two pings to every peer
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// Number of pings in a row a peer may leave unanswered before it is disconnected
+const MAX_MISSED_PONGS: u32 = 3;
+/// Time before dialing again a dropped outgoing peer, doubled after every failed attempt
+const REDIAL_DELAY: Duration = Duration::from_secs(5);
+const MAX_REDIAL_DELAY: Duration = Duration::from_secs(10 * 60);
+/// Number of failed attempts after which a dropped outgoing peer is given up
+const MAX_REDIALS: u32 = 10;
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    network_id: u32,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    outbound_peers: usize,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let addr_book = Arc::new(Mutex::new(AddrBook::new()));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        addr_book: Arc::clone(&addr_book),
     };
+    let genesis = blockchain.lock().unwrap().genesis();
     let ctx = Context {
         peers: std::collections::HashMap::new(),
         addr,
         control_chan: control_signal_receiver,
-        control_sender: control_signal_sender,
-        new_msg_chan: msg_sink,
+        outbound_peers,
+        dialing: HashSet::new(),
+        liveness: HashMap::new(),
+        redials: HashMap::new(),
+        registrar: Registrar {
+            network_id,
+            genesis,
+            listen_port: addr.port(),
+            nonce: rand::random(),
+            blockchain: Arc::clone(blockchain),
+            addr_book,
+            control_chan: control_signal_sender,
+            new_msg_chan: msg_sink,
+        },
     };
     Ok((ctx, handle))
 }
@@ -35,7 +72,87 @@ pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
-    control_sender: smol::channel::Sender<ControlSignal>,
+    /// number of outgoing peers the server keeps by dialing addresses from the address book
+    outbound_peers: usize,
+    /// addresses being dialed
+    dialing: HashSet<std::net::SocketAddr>,
+    liveness: HashMap<std::net::SocketAddr, Liveness>,
+    /// outgoing peers to dial again, because they dropped or could not be reached
+    redials: HashMap<std::net::SocketAddr, Backoff>,
+    registrar: Registrar,
+}
+
+/// Pings sent to a peer and not answered yet, and the round-trip times of the answered ones
+#[derive(Default)]
+struct Liveness {
+    /// nonce and send time of the last ping
+    ping: Option<(u64, Instant)>,
+    /// number of pings in a row the peer did not answer
+    missed: u32,
+    /// round-trip time of the last answered ping
+    rtt: Option<Duration>,
+    /// round-trip time averaged over the answered pings, weighing the last one by 1/8
+    smoothed_rtt: Option<Duration>,
+}
+
+impl Liveness {
+    fn answered(&mut self, rtt: Duration) {
+        self.ping = None;
+        self.missed = 0;
+        self.rtt = Some(rtt);
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+            None => rtt,
+        });
+    }
+}
+
+/// Round-trip times of the pings answered by a peer, in milliseconds, none before the first pong.
+#[derive(Serialize, Debug, Clone)]
+pub struct RoundTrip {
+    pub peer: std::net::SocketAddr,
+    pub last: Option<f64>,
+    pub smoothed: Option<f64>,
+}
+
+/// Schedule of the next attempt to dial a peer, backing off exponentially
+struct Backoff {
+    failures: u32,
+    next: Instant,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff { failures: 0, next: Instant::now() + REDIAL_DELAY }
+    }
+
+    fn failed(&mut self) {
+        self.failures += 1;
+        let delay = REDIAL_DELAY.saturating_mul(1 << self.failures.min(16));
+        self.next = Instant::now() + delay.min(MAX_REDIAL_DELAY);
+    }
+}
+
+/// Outcome of dialing a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DialOutcome {
+    Connected,
+    /// the peer could not be reached, and may be dialed again
+    Failed,
+    /// the peer is on another chain or is ourselves, and is not dialed again
+    Rejected,
+}
+
+/// What the tasks registering new peers need from the server context
+#[derive(Clone)]
+struct Registrar {
+    network_id: u32,
+    genesis: H256,
+    listen_port: u16,
+    nonce: u64,
+    blockchain: Arc<Mutex<Blockchain>>,
+    addr_book: Arc<Mutex<AddrBook>>,
+    control_chan: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
 }
 
@@ -45,7 +162,7 @@ impl Context {
         // initialize the server socket
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
-        let control_chan = self.control_sender.clone();
+        let control_chan = self.registrar.control_chan.clone();
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -53,6 +170,9 @@ impl Context {
             self.dispatch_control(ex_clone).await.unwrap();
         })
             .detach();
+        // periodically top up the outgoing peers, and check that the peers are alive
+        Self::every(&ex, control_chan.clone(), CONNECTION_INTERVAL, || ControlSignal::ManageConnections);
+        Self::every(&ex, control_chan.clone(), PING_INTERVAL, || ControlSignal::PingPeers);
         ex.spawn(async move {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
@@ -61,6 +181,24 @@ impl Context {
         return Ok(());
     }
 
+    /// Send a control signal at a regular interval
+    fn every(
+        ex: &Arc<Executor<'static>>,
+        control_chan: smol::channel::Sender<ControlSignal>,
+        interval: Duration,
+        signal: fn() -> ControlSignal,
+    ) {
+        ex.spawn(async move {
+            loop {
+                Timer::after(interval).await;
+                if control_chan.send(signal()).await.is_err() {
+                    break;
+                }
+            }
+        })
+            .detach();
+    }
+
     /// the loop that endlessly accept incoming peers
     async fn listener_loop(
         listener: Async<net::TcpListener>,
@@ -76,14 +214,15 @@ impl Context {
         }
     }
 
-    async fn dispatch_control(mut self, ex: Arc<Executor<'_>>) -> std::io::Result<()> {
+    async fn dispatch_control(mut self, ex: Arc<Executor<'static>>) -> std::io::Result<()> {
         // read the next control signal
         while let Ok(ctrl) = self.control_chan.recv().await {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    // keep dialing a requested peer that cannot be reached yet
+                    self.redials.insert(addr, Backoff::new());
+                    self.dial(addr, Some(result_chan), &ex);
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
@@ -93,128 +232,279 @@ impl Context {
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    let registrar = self.registrar.clone();
+                    ex.spawn(Self::accept(registrar, stream, ex.clone())).detach();
+                }
+                ControlSignal::NewPeer(handle) => {
+                    trace!("Processing NewPeer({})", handle.addr());
+                    self.liveness.insert(*handle.addr(), Liveness::default());
+                    self.peers.insert(*handle.addr(), handle);
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
+                    self.liveness.remove(&addr);
+                    if let Some(handle) = self.peers.remove(&addr) {
+                        // the peer was alive until now
+                        self.registrar.addr_book.lock().unwrap().seen(*handle.listen_addr());
+                        if handle.direction() == peer::Direction::Outgoing {
+                            self.redials.insert(addr, Backoff::new());
+                        }
+                    }
                     info!("Peer {} disconnected", addr);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::DialFinished(addr, outcome) => {
+                    trace!("Processing DialFinished({}, {:?})", addr, outcome);
+                    self.dialing.remove(&addr);
+                    match outcome {
+                        DialOutcome::Connected | DialOutcome::Rejected => {
+                            self.redials.remove(&addr);
+                        }
+                        DialOutcome::Failed => {
+                            if let Some(backoff) = self.redials.get_mut(&addr) {
+                                backoff.failed();
+                                if backoff.failures > MAX_REDIALS {
+                                    info!("Giving up on peer {} after {} attempts", addr, backoff.failures);
+                                    self.redials.remove(&addr);
+                                }
+                            }
+                        }
+                    }
+                }
+                ControlSignal::PingPeers => {
+                    trace!("Processing PingPeers command");
+                    self.ping_peers();
+                }
+                ControlSignal::Pong(addr, nonce) => {
+                    trace!("Processing Pong({}, {})", addr, nonce);
+                    let liveness = match self.liveness.get_mut(&addr) {
+                        Some(liveness) => liveness,
+                        None => continue,
+                    };
+                    match liveness.ping {
+                        Some((ping, sent)) if ping == nonce => {
+                            debug!("Peer {} answered a ping in {:?}", addr, sent.elapsed());
+                            liveness.answered(sent.elapsed());
+                        }
+                        _ => debug!("Unexpected pong from peer {}", addr),
+                    }
+                }
+                ControlSignal::RoundTrips(result_chan) => {
+                    trace!("Processing RoundTrips command");
+                    let millis = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+                    let round_trips = self
+                        .peers
+                        .keys()
+                        .map(|addr| {
+                            let liveness = self.liveness.get(addr);
+                            RoundTrip {
+                                peer: *addr,
+                                last: millis(liveness.and_then(|liveness| liveness.rtt)),
+                                smoothed: millis(liveness.and_then(|liveness| liveness.smoothed_rtt)),
+                            }
+                        })
+                        .collect();
+                    let _ = result_chan.send(round_trips);
+                }
+                ControlSignal::ManageConnections => {
+                    trace!("Processing ManageConnections command");
+                    self.manage_connections(&ex);
+                }
+                ControlSignal::SendToPeer((receiver, msg)) => {
+                    trace!("Processing SendToPeer({})", receiver);
+                    match self.peers.get_mut(&receiver) {
+                        Some(hd) => hd.write(msg),
+                        None => debug!("Dropping message to disconnected peer {}", receiver),
+                    }
                 }
             }
         }
         return Ok(());
     }
 
+    /// Dial a peer in a new task, so that a slow peer does not hold up the others
+    fn dial(
+        &mut self,
+        addr: std::net::SocketAddr,
+        result_chan: Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+        ex: &Arc<Executor<'static>>,
+    ) {
+        self.dialing.insert(addr);
+        let registrar = self.registrar.clone();
+        ex.spawn(Self::connect(registrar, addr, ex.clone(), result_chan)).detach();
+    }
+
+    /// Ping every peer with a new nonce, and disconnect the peers that left the last
+    /// `MAX_MISSED_PONGS` pings unanswered
+    fn ping_peers(&mut self) {
+        for (addr, hd) in self.peers.iter_mut() {
+            let liveness = self.liveness.entry(*addr).or_default();
+            if liveness.ping.is_some() {
+                liveness.missed += 1;
+            }
+            if liveness.missed >= MAX_MISSED_PONGS {
+                info!("Peer {} missed {} pings, disconnecting", addr, liveness.missed);
+                hd.disconnect();
+                continue;
+            }
+            let nonce = rand::random();
+            liveness.ping = Some((nonce, Instant::now()));
+            hd.write(Message::Ping(nonce));
+        }
+    }
+
+    /// Dial the dropped outgoing peers whose backoff has elapsed, then addresses from the address
+    /// book until the server has `outbound_peers` outgoing peers. Asks the peers for more
+    /// addresses when the book runs short of them.
+    fn manage_connections(&mut self, ex: &Arc<Executor<'static>>) {
+        let now = Instant::now();
+        let due: Vec<std::net::SocketAddr> = self
+            .redials
+            .iter()
+            .filter(|(addr, backoff)| backoff.next <= now && !self.dialing.contains(addr) && !self.peers.contains_key(addr))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in due {
+            debug!("Dialing dropped peer {} again", addr);
+            self.dial(addr, None, ex);
+        }
+
+        let outgoing = self.peers.values().filter(|hd| hd.direction() == peer::Direction::Outgoing).count();
+        let missing = self.outbound_peers.saturating_sub(outgoing + self.dialing.len());
+        if missing == 0 {
+            return;
+        }
+        let connected: HashSet<std::net::SocketAddr> = self.peers.values().map(|hd| *hd.listen_addr()).collect();
+        let local = self.addr;
+        let candidates = self.registrar.addr_book.lock().unwrap().select(missing, |addr| {
+            connected.contains(addr) || self.dialing.contains(addr) || *addr == local
+        });
+        if candidates.len() < missing {
+            for (_, hd) in self.peers.iter_mut() {
+                hd.write(Message::GetAddr);
+            }
+        }
+        for addr in candidates {
+            debug!("Dialing peer {} from the address book", addr);
+            self.dial(addr, None, ex);
+        }
+    }
+
     /// Connect to a peer, and register this peer
     async fn connect(
-        &mut self,
-        addr: &std::net::SocketAddr,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
+        registrar: Registrar,
+        addr: std::net::SocketAddr,
+        ex: Arc<Executor<'static>>,
+        result_chan: Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+    ) {
         debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
-
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+        let control_chan = registrar.control_chan.clone();
+        let result = match Async::<std::net::TcpStream>::connect(addr).await {
+            // register the new peer
+            Ok(stream) => Self::register(registrar, stream, peer::Direction::Outgoing, ex).await,
+            Err(e) => Err(e),
+        };
+        let outcome = match &result {
+            Ok(_) => DialOutcome::Connected,
+            Err(e) => {
+                info!("Could not connect to peer {}: {}", addr, e);
+                match e.kind() {
+                    io::ErrorKind::InvalidData | io::ErrorKind::AddrInUse => DialOutcome::Rejected,
+                    _ => DialOutcome::Failed,
+                }
+            }
+        };
+        control_chan.send(ControlSignal::DialFinished(addr, outcome)).await.unwrap();
+        if let Some(result_chan) = result_chan {
+            let _ = result_chan.send(result);
+        }
     }
 
     async fn accept(
-        &mut self,
+        registrar: Registrar,
         stream: Async<net::TcpStream>,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
-        Ok(())
+        ex: Arc<Executor<'static>>,
+    ) {
+        let addr = match stream.get_ref().peer_addr() {
+            Ok(addr) => addr,
+            // the peer is already gone
+            Err(_) => return,
+        };
+        if let Err(e) = Self::register(registrar, stream, peer::Direction::Incoming, ex).await {
+            info!("Incoming peer {} rejected: {}", addr, e);
+        }
     }
 
     async fn register(
-        &mut self,
+        registrar: Registrar,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
-        ex: Arc<Executor<'_>>,
+        direction: peer::Direction,
+        ex: Arc<Executor<'static>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
-
+        let addr = stream.get_ref().peer_addr()?;
         let stream = AsyncArc::new(stream);
-        let new_msg_chan = self.new_msg_chan.clone();
+
+        // agree on the protocol and the chain before exchanging any other message
+        let (local, tip) = registrar.version();
+        let handshake = Self::handshake(stream.clone(), &local);
+        let timeout = async {
+            Timer::after(HANDSHAKE_TIMEOUT).await;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))
+        };
+        let remote = smol::future::or(handshake, timeout).await?;
+        let listen_addr = std::net::SocketAddr::new(addr.ip(), remote.listen_port);
+        if remote.nonce == local.nonce {
+            // never dial this address again
+            registrar.addr_book.lock().unwrap().remove(&listen_addr);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "connected to ourselves"));
+        }
+        info!("Peer {} on protocol {} at height {}", addr, remote.version, remote.best_height);
+        registrar.addr_book.lock().unwrap().seen(listen_addr);
+
+        let (mut write_queue, mut handle) = peer::new(&stream, direction, listen_addr)?;
+        let new_msg_chan = registrar.new_msg_chan.clone();
         let handle_copy = handle.clone();
-        let control_chan = self.control_sender.clone();
-        let addr = stream.get_ref().peer_addr()?;
+        let control_chan = registrar.control_chan.clone();
+        // insert the peer handle so that we can broadcast to this guy later, before the writer
+        // can report the peer as dropped
+        control_chan.send(ControlSignal::NewPeer(handle.clone())).await.unwrap();
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
         let mut reader = BufReader::new(stream.clone());
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
+            // read the frames, a 4-byte length followed by the message, until the peer disconnects
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
+                let new_payload = match read_frame(&mut reader).await {
+                    Ok(new_payload) => new_payload,
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            info!("Malformed frame from peer {}: {}", addr, e);
+                        }
                         break;
                     }
                 };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
+                new_msg_chan
+                    .send((new_payload, handle_copy.clone()))
                     .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    Err(_) => {
-                        break;
-                    }
-                }
+                    .unwrap();
             }
-            // the peer is disconnected
+            // the peer is disconnected, stop the writer too
+            handle_copy.disconnect();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
+            // get the messages to write from the queue, until the peer is disconnected
+            while let Some(new_msg) = write_queue.next().await {
+                // write the frame header and the payload
+                if write_frame(&mut writer, &new_msg).await.is_err() {
+                    break;
                 }
             }
-            // the peer is disconnected
+            // close the connection, which stops the reader too
+            let _ = writer.get_ref().get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -222,15 +512,114 @@ impl Context {
         })
             .detach();
 
-        // insert the peer handle so that we can broadcast to this guy later
-        self.peers.insert(addr, handle.clone());
+        // let a peer behind us fetch the blocks it misses in one batch, lowest first, so that it
+        // connects them in order instead of walking back from our tip one orphan at a time
+        if local.best_height > remote.best_height {
+            handle.write(Message::NewBlockHashes(registrar.blocks_above(tip, remote.best_height)));
+        }
+        // learn the peers of the peers we dial
+        if direction == peer::Direction::Outgoing {
+            handle.write(Message::GetAddr);
+        }
         Ok(handle)
     }
+
+    /// Exchange `Version` and `Verack` with a new peer, and check that the peer is on the same
+    /// protocol and chain. Returns the version of the peer.
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, local: &Version) -> io::Result<Version> {
+        write_message(&mut stream, &Message::Version(local.clone())).await?;
+        let remote = match read_message(&mut stream).await? {
+            Message::Version(remote) => remote,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a version message")),
+        };
+        if let Err(e) = local.check(&remote) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        }
+        write_message(&mut stream, &Message::Verack).await?;
+        match read_message(&mut stream).await? {
+            Message::Verack => Ok(remote),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected a verack message")),
+        }
+    }
+}
+
+impl Registrar {
+    /// Get the version announced to new peers, and the tip of the chain it describes
+    fn version(&self) -> (Version, H256) {
+        let chain = self.blockchain.lock().unwrap();
+        let tip = chain.tip();
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            network_id: self.network_id,
+            genesis: self.genesis,
+            best_height: chain.height(&tip).unwrap(),
+            listen_port: self.listen_port,
+            nonce: self.nonce,
+        };
+        (version, tip)
+    }
+
+    /// Get the hashes of the blocks from `tip` down to, and excluding, the given height, lowest
+    /// first and at most `MAX_INVENTORY` of them
+    fn blocks_above(&self, tip: H256, height: u128) -> Vec<H256> {
+        let chain = self.blockchain.lock().unwrap();
+        let mut hashes = Vec::new();
+        let mut current = tip;
+        while chain.height(&current).is_some_and(|current_height| current_height > height) {
+            hashes.push(current);
+            current = chain.blocks[&current].get_parent();
+        }
+        hashes.reverse();
+        hashes.truncate(message::MAX_INVENTORY as usize);
+        hashes
+    }
+}
+
+/// Read a frame, a 4-byte big-endian length followed by the payload. A frame larger than the
+/// largest message of its type is rejected before its payload is read.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut size_buffer: [u8; 4] = [0; 4];
+    reader.read_exact(&mut size_buffer).await?;
+    let size = u32::from_be_bytes(size_buffer);
+    // the payload starts with the variant index of the message
+    let mut tag_buffer: [u8; 4] = [0; 4];
+    if size < 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too short"));
+    }
+    reader.read_exact(&mut tag_buffer).await?;
+    let tag = u32::from_le_bytes(tag_buffer);
+    let max_size = Message::max_size(tag)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown message type {}", tag)))?;
+    if u64::from(size) > max_size {
+        let error = format!("frame of {} bytes above the limit {} of message type {}", size, max_size, tag);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+    let mut payload = vec![0; size as usize];
+    payload[..4].copy_from_slice(&tag_buffer);
+    reader.read_exact(&mut payload[4..]).await?;
+    Ok(payload)
+}
+
+/// Write a frame, a 4-byte big-endian length followed by the payload
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+    let payload = read_frame(reader).await?;
+    Message::decode(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, msg: &Message) -> io::Result<()> {
+    write_frame(writer, &bincode::serialize(msg).unwrap()).await
 }
 
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    addr_book: Arc<Mutex<AddrBook>>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -246,6 +635,15 @@ impl TestReceiver {
             _ => None,
         }
     }
+
+    /// Get the next message sent to a single peer, with the address of the peer
+    pub fn recv_sent(&self) -> Option<(std::net::SocketAddr, message::Message)> {
+        let sig = smol::block_on(self.control_chan.recv()).unwrap();
+        match sig {
+            ControlSignal::SendToPeer((receiver, msg)) => Some((receiver, msg)),
+            _ => None,
+        }
+    }
 }
 
 impl Handle {
@@ -263,14 +661,40 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
+    /// Send a message to the connected peer with the given address
+    pub fn send(&self, receiver: std::net::SocketAddr, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Report a pong received from a peer, answering one of the pings of the server
+    pub fn received_pong(&self, peer: std::net::SocketAddr, nonce: u64) {
+        smol::block_on(self.control_chan.send(ControlSignal::Pong(peer, nonce))).unwrap();
+    }
+
+    /// Get the round-trip times measured by pinging every connected peer
+    pub fn round_trips(&self) -> Vec<RoundTrip> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::RoundTrips(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Get up to `max` of the most recently seen peer addresses
+    pub fn known_addresses(&self, max: usize) -> Vec<PeerAddr> {
+        self.addr_book.lock().unwrap().recent(max)
+    }
+
+    /// Add peer addresses gossiped by a peer to the address book
+    pub fn learn_addresses(&self, addrs: &[PeerAddr]) {
+        let mut addr_book = self.addr_book.lock().unwrap();
+        for peer in addrs {
+            addr_book.add(peer.addr, peer.last_seen);
+        }
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {control_chan: s, addr_book: Arc::new(Mutex::new(AddrBook::new()))};
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -284,5 +708,90 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    NewPeer(peer::Handle),
+    DialFinished(std::net::SocketAddr, DialOutcome),
+    ManageConnections,
+    PingPeers,
+    Pong(std::net::SocketAddr, u64),
+    RoundTrips(oneshot::Sender<Vec<RoundTrip>>),
+    SendToPeer((std::net::SocketAddr, message::Message)),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::genesis::GenesisSpec;
+    use crate::blockchain::state::LedgerModel;
+    use ntest::timeout;
+
+    fn start_server(port: u16, network_id: u32, spec: &GenesisSpec) -> (Handle, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        let blockchain = Arc::new(Mutex::new(Blockchain::from_genesis(spec, LedgerModel::Account)));
+        let (msg_sink, msg_src) = smol::channel::bounded(10000);
+        let (ctx, handle) = new(([127, 0, 0, 1], port).into(), msg_sink, network_id, &blockchain, 0).unwrap();
+        ctx.start().unwrap();
+        (handle, msg_src)
+    }
+
+    #[test]
+    fn reject_oversized_frames() {
+        let frame = |size: u32, tag: u32| {
+            let mut bytes = size.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&tag.to_le_bytes());
+            bytes.resize(4 + size.max(4) as usize, 0);
+            bytes
+        };
+        let read = |bytes: Vec<u8>| smol::block_on(read_frame(&mut futures::io::Cursor::new(bytes)));
+        // a ping holds a nonce
+        assert_eq!(read(frame(12, 0)).unwrap(), frame(12, 0)[4..].to_vec());
+        assert_eq!(read(frame(13, 0)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // a huge frame is rejected from its header alone
+        let mut huge = u32::MAX.to_be_bytes().to_vec();
+        huge.extend_from_slice(&4u32.to_le_bytes());
+        assert_eq!(read(huge).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(frame(12, 99)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(vec![0, 0, 0, 2, 0, 0]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn redial_backoff() {
+        let mut backoff = Backoff::new();
+        let mut delays = Vec::new();
+        for _ in 0..10 {
+            backoff.failed();
+            delays.push((backoff.next - Instant::now() + Duration::from_millis(500)).as_secs());
+        }
+        assert_eq!(delays, vec![10, 20, 40, 80, 160, 320, 600, 600, 600, 600]);
+    }
+
+    #[test]
+    fn smooth_round_trip() {
+        let mut liveness = Liveness { ping: Some((1, Instant::now())), missed: 2, ..Liveness::default() };
+        liveness.answered(Duration::from_millis(80));
+        assert_eq!((liveness.ping, liveness.missed), (None, 0));
+        assert_eq!(liveness.smoothed_rtt, Some(Duration::from_millis(80)));
+        liveness.answered(Duration::from_millis(160));
+        assert_eq!(liveness.rtt, Some(Duration::from_millis(160)));
+        assert_eq!(liveness.smoothed_rtt, Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn version_handshake() {
+        let spec = GenesisSpec::default();
+        let other_spec = GenesisSpec { timestamp: 1, ..GenesisSpec::default() };
+        let (server, _msgs) = start_server(17301, 0, &spec);
+        let (_same, _same_msgs) = start_server(17302, 0, &spec);
+        let (_other_network, _other_network_msgs) = start_server(17303, 1, &spec);
+        let (_other_chain, _other_chain_msgs) = start_server(17304, 0, &other_spec);
+        assert!(server.connect(([127, 0, 0, 1], 17302).into()).is_ok());
+        let round_trips = server.round_trips();
+        assert_eq!(round_trips.len(), 1);
+        assert_eq!(round_trips[0].peer, ([127, 0, 0, 1], 17302).into());
+        assert!(round_trips[0].last.is_none());
+        // peers on another network or chain are disconnected during the handshake
+        let e = server.connect(([127, 0, 0, 1], 17303).into()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = server.connect(([127, 0, 0, 1], 17304).into()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
 }
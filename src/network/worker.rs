@@ -1,11 +1,15 @@
-use super::message::Message;
+use super::addrbook::MAX_ADDRESSES;
+use super::message::{Message, HEADER_SIZE, MAX_FRAME_SIZE};
+use super::orphans::OrphanPool;
 use super::peer;
 use super::server::Handle as ServerHandle;
 use crate::types::hash::{H256, Hashable};
 use crate::types::block::Block;
 use crate::Blockchain;
-use crate::types::transaction::{self, SignedTransaction, Transaction, verify};
-use std::collections::HashMap;
+use crate::mempool::{Mempool, MempoolError};
+use crate::consensus::{self, ValidationError};
+use crate::miner::Handle as MinerHandle;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
 use log::{debug, warn, error};
@@ -17,13 +21,20 @@ use std::thread;
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test,test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+#[cfg(any(test,test_utilities))]
+use crate::blockchain::genesis::GenesisSpec;
+#[cfg(any(test,test_utilities))]
+use crate::blockchain::state::LedgerModel;
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     chain: Arc<Mutex<Blockchain>>,
-    mem_pool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    mem_pool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
+    /// blocks waiting for their parent, shared by the worker threads
+    orphans: Arc<Mutex<OrphanPool>>,
 }
 
 
@@ -33,14 +44,17 @@ impl Worker {
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         chain: &Arc<Mutex<Blockchain>>,
-        mem_pool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+        mem_pool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             chain: Arc::clone(chain),
-            mem_pool: Arc::clone(mem_pool)
+            mem_pool: Arc::clone(mem_pool),
+            miner: miner.clone(),
+            orphans: Arc::new(Mutex::new(OrphanPool::new())),
         }
     }
 
@@ -55,8 +69,64 @@ impl Worker {
         }
     }
 
+    /// Validate a block received from a peer and insert it into the chain, followed by the buffered
+    /// orphans that descend from it. Blocks whose parent is unknown are buffered with the peer
+    /// they came from, if their hash meets a target the chain allows, and their parent requested
+    /// from that peer.
+    fn process_block(&self, chain: &mut Blockchain, block: Block, source: SocketAddr, new_blocks: &mut Vec<H256>) {
+        let mut queue = vec![(block, source)];
+        while let Some((block, source)) = queue.pop() {
+            let hash = block.hash();
+            if chain.blocks.contains_key(&hash) {
+                continue;
+            }
+            match consensus::validate_block(chain, &block) {
+                Ok(()) => {}
+                Err(ValidationError::UnknownParent(parent)) => {
+                    let difficulty = block.get_difficulty();
+                    if hash > difficulty || difficulty > chain.params().initial_difficulty {
+                        warn!("Orphan block {} rejected: insufficient work", hash);
+                        continue;
+                    }
+                    if self.orphans.lock().unwrap().insert(block, source) {
+                        self.server.send(source, Message::GetBlocks(vec![parent]));
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Block {} rejected: {}", hash, e);
+                    continue;
+                }
+            }
+            let reorg = match chain.insert(&block) {
+                Ok(reorg) => reorg,
+                Err(e) => {
+                    warn!("Block {} rejected: {}", hash, e);
+                    continue;
+                }
+            };
+            // the transactions of the block are confirmed, remove them from the mem pool
+            let mut mem_pool = self.mem_pool.lock().unwrap();
+            mem_pool.remove_confirmed(&block.data.data);
+            // if the tip moved, put the transactions of disconnected blocks back into the mem pool,
+            // check the pending transactions against the new tip and let the miner build on it
+            if let Some(reorg) = reorg {
+                let state = chain.state(&chain.tip()).unwrap();
+                for transaction in chain.orphaned_transactions(&reorg) {
+                    let _ = mem_pool.insert(transaction, state);
+                }
+                let dropped = mem_pool.revalidate(state);
+                debug!("Dropped {} pending transactions invalid on the new tip", dropped);
+                self.miner.update();
+            }
+            drop(mem_pool);
+            new_blocks.push(hash);
+            // the orphans waiting for this block can now be processed
+            queue.extend(self.orphans.lock().unwrap().take_children(&hash));
+        }
+    }
+
     fn worker_loop(&self) {
-        let mut orphan_buffer: Vec<Block> = Vec::new();
         loop {
             let result = smol::block_on(self.msg_chan.recv());
             if let Err(e) = result {
@@ -65,14 +135,22 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg = match Message::decode(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Malformed message from peer {}, disconnecting: {}", peer.addr(), e);
+                    peer.disconnect();
+                    continue;
+                }
+            };
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+                    peer.write(Message::Pong(nonce));
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    self.server.received_pong(*peer.addr(), nonce);
                 }
 
                 // Takes the vec of block hashes, checks if they are already in the chain
@@ -107,12 +185,18 @@ impl Worker {
 
                     // vec to store requested blocks
                     let mut blocks = Vec::new();
+                    // the reply must fit in a frame, the peer asks again for the blocks left out
+                    let mut size = 0;
 
                     // iterate through requested blocks 
                     for i in 0..block_hashes.len() {
                         // check if the block is in the chain
-                        if chain.blocks.contains_key(&block_hashes[i]) {
-                            blocks.push(chain.blocks.get(&block_hashes[i]).unwrap().clone());
+                        if let Some(block) = chain.blocks.get(&block_hashes[i]) {
+                            size += bincode::serialized_size(block).unwrap();
+                            if size > MAX_FRAME_SIZE - HEADER_SIZE {
+                                break;
+                            }
+                            blocks.push(block.clone());
                         }
                     }
                     println!("Blocks len: {:?}", blocks.len());
@@ -126,85 +210,18 @@ impl Worker {
                 }
 
                 // handles received blocks
-                Message::Blocks(blocks) =>{
+                Message::Blocks(blocks) => {
                     let mut chain = self.chain.lock().unwrap();
                     // vec of new blocks
                     let mut new_blocks = Vec::new();
-                    // iterate over received blocks
-                    for i in 0..blocks.len() {                        
-                        // check if the chain already contains the block. If not, proceed with validity checks
-                        if !(chain.blocks.contains_key(&blocks[i].hash())) {
-                            println!("Check 1 Passed: Block is not already in chain.");
-                            // check if the chain contains the blocks parent
-                            if chain.blocks.contains_key(&blocks[i].get_parent()) {
-                                println!("Check 2 Passed: Chain does contain block's parent");
-                                // get parents/current difficulty
-                                let difficulty = chain.blocks.get(&blocks[i].get_parent()).unwrap().get_difficulty();
-                                
-                                if blocks[i].hash() <= difficulty {
-                                    println!("Check 3 Passed: PoW validity check for difficulty={:?}", difficulty);
-                                    // INSERT CHECK THAT THE SENDER HAS SUFFICIENT FUNDS FOR THE TRANSACTION BEFORE ADDING IT
-                                    let transactions = blocks[i].data.data.clone();
-                                    let mut invalidBlock = false;
-                                    for transaction in transactions.clone() {
-                                        // do not include the block if there's even a one non-valid transaction
-                                        if !verify(&transaction.transaction, &transaction.pubkey, &transaction.signature) {
-                                            println!("Transaction not properly signed. Block disregarded.");
-                                            invalidBlock = true;
-                                            
-                                        }
-                                    }
-                                    // disregard current block and continue processing the next one
-                                    if invalidBlock {
-                                        continue;
-                                    }
-                                    // if all transactions are valid remove them from mem pool and insert the block to blockchain
-                                    for transaction in transactions {
-                                        // get the lock for the mem_pool
-                                        let mut mem_pool = self.mem_pool.lock().unwrap();
-                                        // remove transaction from the mempool
-                                        mem_pool.remove(&transaction.hash());
-                                    }
-                                    println!("We got to here.");
-                                    chain.insert(&blocks[i].clone()); // (&blocks[i].clone());
-                                    new_blocks.push(blocks[i].hash());
-                                    
-                                    // check if the processed block is the parent of any of the blocks in orphan_buffer
-                                    // if so, process the orphan block
-                                    let mut remove = false;
-                                    for mut j in 0..orphan_buffer.len() {
-                                        if remove {
-                                            j = j - 1;
-                                        }
-                                        if &blocks[i].hash() == &orphan_buffer[j].get_parent() {
-                                            remove = true;
-                                            let mut orphans: Vec<Block> = Vec::new();
-                                            orphans.push(orphan_buffer[j].clone());
-
-                                            orphan_buffer.remove(j);
-                                            self.server.broadcast(Message::Blocks(orphans));
-                                        }
-                                    }
-                                }
-                            
-                            }
-                            else {
-                                orphan_buffer.push(blocks[i].clone());
-                                let mut orphans: Vec<H256> = Vec::new();
-                                orphans.push(blocks[i].get_parent());
-                                self.server.broadcast(Message::GetBlocks(orphans));
-                            }
-                        }
-
+                    for block in blocks {
+                        self.process_block(&mut chain, block, *peer.addr(), &mut new_blocks);
                     }
                     // broadcast all inserted blocks
-                    if new_blocks.len() != 0 {
-                        println!("Broadcasting the new blocks in Blocks()");
+                    if !new_blocks.is_empty() {
+                        debug!("Broadcasting {} new blocks", new_blocks.len());
                         self.server.broadcast(Message::NewBlockHashes(new_blocks));
                     }
-                    
-                    // drop the lock
-                    // drop(chain); 
                 }
 
                 Message::NewTransactionHashes(transaction_hashes) => {
@@ -214,7 +231,7 @@ impl Worker {
                     for transaction in transaction_hashes {
                         let mem_pool = self.mem_pool.lock().unwrap();
                         // check
-                        if !mem_pool.contains_key(&transaction) {
+                        if !mem_pool.contains(&transaction) {
                             new_transactions.push(transaction.clone());
                             // print here for tests
                         }
@@ -227,12 +244,17 @@ impl Worker {
                 }
                 Message::GetTransactions(transaction_hashes) => {
                     let mut transactions = Vec::new();
+                    let mut size = 0;
                     // go through new transactions and check if you have them in the mempool
                     // if so, push them to the vec and broadcast it with Transaction() msg
                     for transaction in transaction_hashes {
                         let mem_pool = self.mem_pool.lock().unwrap();
-                        if mem_pool.contains_key(&transaction) {
-                            transactions.push(mem_pool[&transaction].clone());
+                        if let Some(transaction) = mem_pool.get(&transaction) {
+                            size += transaction.size();
+                            if size > MAX_FRAME_SIZE - HEADER_SIZE {
+                                break;
+                            }
+                            transactions.push(transaction.clone());
                             // print stuff for tests
                         }
                     }
@@ -245,33 +267,44 @@ impl Worker {
                 }
                 Message::Transactions(transactions) => {
                     let mut new_transactions = Vec::new();
-                    // check if transactions are properly signed using public key
+                    // check the transactions against the tip state before adding them to the mempool
+                    let chain = self.chain.lock().unwrap();
+                    let state = chain.state(&chain.tip()).unwrap();
+                    let mut mem_pool = self.mem_pool.lock().unwrap();
                     for transaction in transactions {
-                        let mut mem_pool = self.mem_pool.lock().unwrap();
-                        // if SignedTransaction is not properly signed, remove it from the mem_pool and continue
-                        if !verify(&transaction.transaction, &transaction.pubkey, &transaction.signature) {
-                            mem_pool.remove(&transaction.hash());
-                            continue;
-                        }
-
-                        let transaction = transaction.clone();
-
                         let hash = transaction.hash();
-                        // add transaction to the mempool if it is not already in it
-                        // add its hash to new_transactions vec
-                        if !(mem_pool.contains_key(&hash)) {
-                            new_transactions.push(transaction.clone().hash());
-                            mem_pool.insert(hash, transaction.clone());
+                        // new transactions and fee bumping replacements are relayed alike
+                        match mem_pool.insert(transaction, state) {
+                            Ok(hash) => new_transactions.push(hash),
+                            Err(MempoolError::AlreadyKnown) => {}
+                            Err(e) => debug!("Transaction {} rejected: {}", hash, e),
                         }
                     }
+                    drop(mem_pool);
+                    drop(chain);
 
                     // broadcast the hashes of new transactions
                     if new_transactions.len() != 0 {
+                        // let the miner pick up the new transactions
+                        self.miner.update();
                         println!("Broadcasting hashes of new transactions in Transactions MSG");
                         self.server.broadcast(Message::NewTransactionHashes(new_transactions));
                     }
                 }
-                _ => unimplemented!()   
+                Message::GetAddr => {
+                    peer.write(Message::Addr(self.server.known_addresses(MAX_ADDRESSES)));
+                }
+                Message::Addr(addrs) => {
+                    if addrs.len() > MAX_ADDRESSES {
+                        debug!("Ignoring {} addresses from {}", addrs.len(), peer.addr());
+                        continue;
+                    }
+                    self.server.learn_addresses(&addrs);
+                }
+                Message::Version(_) | Message::Verack => {
+                    // the handshake is over once the server hands messages of the peer to the workers
+                    debug!("Unexpected handshake message from {}", peer.addr());
+                }
             }
         }
     }
@@ -289,7 +322,10 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_bytes(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -300,12 +336,18 @@ impl TestMsgSender {
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
     let (server, server_receiver) = ServerHandle::new_for_test();
     
-    let mut blockchain = Blockchain::new();
+    // the easiest genesis target, so that random blocks pass the PoW check
+    let mut spec = GenesisSpec::default();
+    spec.params.initial_difficulty = [255u8; 32].into();
+    let blockchain = Blockchain::from_genesis(&spec, LedgerModel::Account);
     let longest_chain = blockchain.all_blocks_in_longest_chain();
     let blockchain = Arc::new(Mutex::new(blockchain));
+    let mem_pool = Arc::new(Mutex::new(Mempool::default()));
+    let (miner_ctx, miner, _finished_block_chan) = crate::miner::new(&blockchain, &mem_pool, [0u8; 20].into(), 1);
+    miner_ctx.start();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
-    // let worker = Worker::new(1, msg_chan, &server, &blockchain);
-    // worker.start(); 
+    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mem_pool, &miner);
+    worker.start(); 
     (test_msg_sender, server_receiver, longest_chain)
 }
 
@@ -317,7 +359,7 @@ mod test {
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
 
-    use super::super::message::Message;
+    use super::super::message::{Message, PeerAddr};
     use super::generate_test_worker_and_start;
 
     #[test]
@@ -360,6 +402,112 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn request_orphan_parent_from_source() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let parent = generate_random_block(&[7u8; 32].into());
+        let orphan = generate_random_block(&parent.hash());
+        // the test peer handles all have the same address
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![orphan]));
+        let (receiver, reply) = server_receiver.recv_sent().unwrap();
+        assert_eq!(receiver, std::net::SocketAddr::from(([127, 0, 0, 1], 12321)));
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![parent.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn ignore_orphan_without_work() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let mut forged = generate_random_block(&[7u8; 32].into());
+        forged.header.difficulty = [0u8; 32].into();
+        let parent = generate_random_block(&[8u8; 32].into());
+        let orphan = generate_random_block(&parent.hash());
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![forged, orphan]));
+        // only the parent of the orphan meeting its target is requested
+        let (_, reply) = server_receiver.recv_sent().unwrap();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![parent.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_addr() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let peer = PeerAddr { addr: ([10, 0, 0, 1], 6000).into(), last_seen: 1 };
+        let recent = PeerAddr { addr: ([10, 0, 0, 2], 6000).into(), last_seen: u128::MAX };
+        test_msg_sender.send(Message::Addr(vec![peer, recent]));
+        let mut peer_receiver = test_msg_sender.send(Message::GetAddr);
+        // the address seen too long ago is dropped, the one from the future is capped to now
+        if let Message::Addr(v) = peer_receiver.recv() {
+            assert_eq!(v.len(), 1);
+            assert_eq!(v[0].addr, recent.addr);
+            assert!(v[0].last_seen < u128::MAX);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn disconnect_malformed_message() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        // a GetBlocks message claiming more hashes than it holds
+        let mut peer_receiver = test_msg_sender.send_bytes(vec![3, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255]);
+        assert!(peer_receiver.disconnected());
+        // the worker keeps serving the other peers
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![*v.last().unwrap()]));
+        if let Message::Blocks(blocks) = peer_receiver.recv() {
+            assert_eq!(blocks.len(), 1);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn sync_longer_than_orphan_limit() {
+        use super::super::orphans::MAX_ORPHANS_PER_PEER;
+        use super::super::server;
+        use super::Worker;
+        use crate::blockchain::genesis::GenesisSpec;
+        use crate::blockchain::state::LedgerModel;
+        use crate::mempool::Mempool;
+        use crate::Blockchain;
+        use std::sync::{Arc, Mutex};
+
+        let mut spec = GenesisSpec::default();
+        spec.params.initial_difficulty = [255u8; 32].into();
+        spec.params.retarget_interval = 0;
+        let start_node = |port: u16| {
+            let blockchain = Arc::new(Mutex::new(Blockchain::from_genesis(&spec, LedgerModel::Account)));
+            let mem_pool = Arc::new(Mutex::new(Mempool::default()));
+            let (msg_sink, msg_src) = smol::channel::bounded(10000);
+            let (ctx, server) = server::new(([127, 0, 0, 1], port).into(), msg_sink, 0, &blockchain, 0).unwrap();
+            ctx.start().unwrap();
+            let (_miner_ctx, miner, _finished_block_chan) = crate::miner::new(&blockchain, &mem_pool, [0u8; 20].into(), 1);
+            Worker::new(1, msg_src, &server, &blockchain, &mem_pool, &miner).start();
+            (blockchain, server)
+        };
+        let (ahead, _ahead_server) = start_node(17311);
+        let (behind, behind_server) = start_node(17312);
+        {
+            let mut chain = ahead.lock().unwrap();
+            for i in 0..MAX_ORPHANS_PER_PEER as u128 + 8 {
+                let mut block = generate_random_block(&chain.tip());
+                block.header.timestamp = i + 1;
+                chain.insert(&block).unwrap();
+            }
+        }
+        let tip = ahead.lock().unwrap().tip();
+        behind_server.connect(([127, 0, 0, 1], 17311).into()).unwrap();
+        while behind.lock().unwrap().tip() != tip {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
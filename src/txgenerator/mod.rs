@@ -3,7 +3,7 @@ extern crate rand;
 use rand::Rng;
 use log::info;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::ops::Deref;
 use std::time;
 use std::thread;
@@ -12,10 +12,13 @@ use std::sync::Mutex;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
 use crate::types::block::{Block, Data, Header};
-use crate::types::hash::{H256, Hashable};
 // use crate::types::merkle::verify;
-use crate::types::transaction::{SignedTransaction, Transaction, sign, verify}; // state later 
+use crate::types::transaction::{Payload, SignedTransaction, Transaction};
+use crate::types::utxo::{Input, Output, UtxoTransaction};
+use crate::blockchain::state::{AccountState, State};
+use crate::blockchain::utxo::UtxoState;
 use crate::types::merkle::{MerkleTree};
 use crate::types::address::{Address};
 use crate::types::key_pair;
@@ -39,7 +42,10 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     tx_chan: Sender<SignedTransaction>,
-    // state later
+    /// Key of the node, owning the outputs spent in the UTXO model, mining rewards included
+    key: Ed25519KeyPair,
+    /// Outputs already spent by generated transactions
+    spent: HashSet<Input>,
 }
 
 #[derive(Clone)]
@@ -48,7 +54,7 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain:&Arc<Mutex<Blockchain>>, mem_pool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>) -> (Context, Handle, Receiver<SignedTransaction>) {
+pub fn new(blockchain:&Arc<Mutex<Blockchain>>, mem_pool: &Arc<Mutex<Mempool>>, key: Ed25519KeyPair) -> (Context, Handle, Receiver<SignedTransaction>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (tx_chan_sender, tx_chan_receiver) = unbounded();
 
@@ -57,7 +63,8 @@ pub fn new(blockchain:&Arc<Mutex<Blockchain>>, mem_pool: &Arc<Mutex<HashMap<H256
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         tx_chan: tx_chan_sender,
-        // state later
+        key,
+        spent: HashSet::new(),
     };
 
     let handle = Handle {
@@ -70,19 +77,20 @@ pub fn new(blockchain:&Arc<Mutex<Blockchain>>, mem_pool: &Arc<Mutex<HashMap<H256
 }
 
 
+// the signals return false once the generator has shut down and dropped its control channel
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> bool {
+        self.control_chan.send(ControlSignal::Exit).is_ok()
     }
 
-    pub fn start(&self, theta: u64) {
+    pub fn start(&self, theta: u64) -> bool {
         self.control_chan
             .send(ControlSignal::Start(theta))
-            .unwrap();
+            .is_ok()
     }
 
-    pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+    pub fn update(&self) -> bool {
+        self.control_chan.send(ControlSignal::Update).is_ok()
     }
 }
 
@@ -148,32 +156,17 @@ impl Context {
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
-            // get wallets later
-            // state later
-
-            // generate addresses
-            let mut rng = rand::thread_rng();
-            let key1 = key_pair::random();
-            let key2 = key_pair::random();
-            let public_key1 = key1.public_key();
-            let public_key2 = key2.public_key();
-            let mut sender: Address = Address::from_public_key_bytes(public_key1.as_ref());
-            let mut receiver: Address = Address::from_public_key_bytes(public_key2.as_ref());
-            let mut amount = 0;
-            let mut nonce = 0;
-            // generate the transaction
-            let transaction = Transaction{
-                sender, 
-                receiver, 
-                value: amount,  
-                nonce};
-            let signature = sign(&transaction, &key1);
-            let stx = SignedTransaction {
-                transaction,
-                signature: signature.as_ref().to_vec(),
-                pubkey: key1.public_key().as_ref().to_vec(),
+            // generate a transaction in the ledger model of the chain
+            let chain = Arc::clone(&self.chain);
+            let chain = chain.lock().unwrap();
+            let stx = match chain.state(&chain.tip()).unwrap() {
+                State::Account(state) => Some(self.account_transaction(state)),
+                State::Utxo(state) => self.utxo_transaction(state),
             };
-            self.tx_chan.send(stx.clone()).expect("Send finished transaction error");
+            drop(chain);
+            if let Some(stx) = stx {
+                self.tx_chan.send(stx).expect("Send finished transaction error");
+            }
 
             // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
 
@@ -186,4 +179,46 @@ impl Context {
             }
         }
     }
+
+    /// Generate a zero-value transfer between two fresh addresses
+    fn account_transaction(&self, state: &AccountState) -> SignedTransaction {
+        // generate addresses
+        let key1 = key_pair::random();
+        let key2 = key_pair::random();
+        let public_key1 = key1.public_key();
+        let public_key2 = key2.public_key();
+        let sender: Address = Address::from_public_key_bytes(public_key1.as_ref());
+        let receiver: Address = Address::from_public_key_bytes(public_key2.as_ref());
+        let amount = 0;
+        // the next transaction of the sender must carry its nonce + 1
+        let nonce = state.nonce(&sender) + 1;
+        // generate the transaction
+        let transaction = Transaction{
+            sender, 
+            receiver, 
+            value: amount,  
+            nonce,
+            fee: 0};
+        SignedTransaction::from_payload(Payload::Account(transaction), &key1)
+    }
+
+    /// Spend one of the generator's unspent outputs, paying a fee of 1, half of the rest to a fresh
+    /// address and the remainder back to the generator. Returns None if there is nothing left to spend.
+    fn utxo_transaction(&mut self, state: &UtxoState) -> Option<SignedTransaction> {
+        let owner = Address::from_public_key_bytes(self.key.public_key().as_ref());
+        let spent = &self.spent;
+        let (input, output) = state.unspent(&owner).into_iter().find(|(input, _)| !spent.contains(input))?;
+        self.spent.insert(input);
+        let receiver = Address::from_public_key_bytes(key_pair::random().public_key().as_ref());
+        let fee = output.value.min(1);
+        let amount = (output.value - fee) / 2;
+        let transaction = UtxoTransaction {
+            inputs: vec![input],
+            outputs: vec![
+                Output { receiver, value: amount },
+                Output { receiver: owner, value: output.value - fee - amount },
+            ],
+        };
+        Some(SignedTransaction::from_payload(Payload::Utxo(transaction), &self.key))
+    }
 }
@@ -6,14 +6,16 @@ use crate::blockchain::Blockchain;
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use crate::network::message::Message;
-use crate::types::hash::{Hashable, H256};
 use crate::types::transaction::{SignedTransaction};
+use crate::mempool::Mempool;
+use crate::miner::Handle as MinerHandle;
 use std::thread;
-use std::collections::HashMap;
 
 #[derive(Clone)]
 pub struct Worker {
-    mem_pool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    chain: Arc<Mutex<Blockchain>>,
+    mem_pool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
     server: ServerHandle,
     tx_chan: Receiver<SignedTransaction>,
 }
@@ -22,12 +24,16 @@ impl Worker {
     pub fn new(
         server: &ServerHandle,
         tx_chan: Receiver<SignedTransaction>,
-        mem_pool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+        chain: &Arc<Mutex<Blockchain>>,
+        mem_pool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle,
     ) -> Self {
         Self {
             server: server.clone(),
             tx_chan,
+            chain: Arc::clone(chain),
             mem_pool: Arc::clone(&mem_pool),
+            miner: miner.clone(),
         }
     }
 
@@ -43,16 +49,55 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            let transaction: SignedTransaction = self.tx_chan.recv().expect("Receive finished transaction error");
+            let transaction: SignedTransaction = match self.tx_chan.recv() {
+                Ok(transaction) => transaction,
+                Err(_) => {
+                    info!("Transaction generator worker shutting down");
+                    return;
+                }
+            };
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
+            let chain = self.chain.lock().unwrap();
             let mut mem_pool = self.mem_pool.lock().unwrap();
             // insert tx to mempool
-            mem_pool.insert(transaction.clone().hash(), transaction.clone());
-            // broadcast the hash of the new block
+            let hash = match mem_pool.insert(transaction, chain.state(&chain.tip()).unwrap()) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    debug!("Generated transaction rejected: {}", e);
+                    continue;
+                }
+            };
+            drop(mem_pool);
+            drop(chain);
+            // let the miner pick up the new transaction
+            self.miner.update();
+            // broadcast the hash of the new transaction
             let mut vec = Vec::new();
-            vec.push(transaction.clone().hash());
+            vec.push(hash);
             self.server.broadcast(Message::NewTransactionHashes(vec));
-            drop(mem_pool);
         }   
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(60000)]
+    fn exit_with_generator() {
+        let chain = Arc::new(Mutex::new(Blockchain::new()));
+        let mem_pool = Arc::new(Mutex::new(Mempool::default()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (_miner_ctx, miner, _finished_block_chan) = crate::miner::new(&chain, &mem_pool, [0u8; 20].into(), 1);
+        let (txgen_ctx, txgen, tx_chan) = crate::txgenerator::new(&chain, &mem_pool, key_pair::random());
+        let worker = Worker::new(&server, tx_chan, &chain, &mem_pool, &miner);
+        txgen_ctx.start();
+        assert!(txgen.exit());
+        // the worker returns once the generator has shut down and dropped its sender
+        let worker = thread::spawn(move || worker.worker_loop());
+        assert!(worker.join().is_ok());
+    }
+}
@@ -109,6 +109,123 @@ impl PartialOrd for H256 {
     }
 }
 
+impl H256 {
+    /// Multiply the hash, read as a 256-bit big endian integer, by `num / den`,
+    /// saturating at the largest hash
+    pub fn scale(&self, num: u64, den: u64) -> H256 {
+        let limbs = self.limbs();
+        // multiply into five limbs
+        let mut product = [0u64; 5];
+        let mut carry: u128 = 0;
+        for i in (0..4).rev() {
+            let value = limbs[i] as u128 * num as u128 + carry;
+            product[i + 1] = value as u64;
+            carry = value >> 64;
+        }
+        product[0] = carry as u64;
+        // long division by den
+        let mut remainder: u128 = 0;
+        for limb in product.iter_mut() {
+            let value = (remainder << 64) | *limb as u128;
+            *limb = (value / den as u128) as u64;
+            remainder = value % den as u128;
+        }
+        if product[0] != 0 {
+            return [255u8; 32].into();
+        }
+        H256::from_limbs([product[1], product[2], product[3], product[4]])
+    }
+
+    /// Expected number of hashes needed to find a block meeting this target, 2^256 / (target + 1)
+    pub fn work(&self) -> H256 {
+        // 2^256 / (target + 1) = (2^256 - 1 - target) / (target + 1) + 1, which fits in 256 bits
+        let target = self.limbs();
+        let numerator = [!target[0], !target[1], !target[2], !target[3]];
+        let (denominator, overflow) = add_limbs(target, [0, 0, 0, 1]);
+        if overflow {
+            // the target is the largest hash, any hash meets it
+            return H256::from_limbs([0, 0, 0, 1]);
+        }
+        let quotient = div_limbs(numerator, denominator);
+        H256::from_limbs(quotient).saturating_add(&H256::from_limbs([0, 0, 0, 1]))
+    }
+
+    /// Add two hashes as 256-bit integers, saturating at the largest hash
+    pub fn saturating_add(&self, other: &H256) -> H256 {
+        match add_limbs(self.limbs(), other.limbs()) {
+            (_, true) => [255u8; 32].into(),
+            (sum, false) => H256::from_limbs(sum),
+        }
+    }
+
+    // split into 64-bit limbs, most significant first
+    fn limbs(&self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_be_bytes(self.0[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: [u64; 4]) -> H256 {
+        let mut raw = [0u8; 32];
+        for (i, limb) in limbs.iter().enumerate() {
+            raw[i * 8..(i + 1) * 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        raw.into()
+    }
+}
+
+// add two 256-bit integers, returning the sum and whether it overflowed
+fn add_limbs(a: [u64; 4], b: [u64; 4]) -> ([u64; 4], bool) {
+    let mut sum = [0u64; 4];
+    let mut carry = false;
+    for i in (0..4).rev() {
+        let (value, overflow1) = a[i].overflowing_add(b[i]);
+        let (value, overflow2) = value.overflowing_add(carry as u64);
+        sum[i] = value;
+        carry = overflow1 || overflow2;
+    }
+    (sum, carry)
+}
+
+// subtract two 256-bit integers, wrapping around on underflow
+fn sub_limbs(a: [u64; 4], b: [u64; 4]) -> [u64; 4] {
+    let mut difference = [0u64; 4];
+    let mut borrow = false;
+    for i in (0..4).rev() {
+        let (value, underflow1) = a[i].overflowing_sub(b[i]);
+        let (value, underflow2) = value.overflowing_sub(borrow as u64);
+        difference[i] = value;
+        borrow = underflow1 || underflow2;
+    }
+    difference
+}
+
+// divide two 256-bit integers by binary long division, the divisor must not be 0
+fn div_limbs(numerator: [u64; 4], denominator: [u64; 4]) -> [u64; 4] {
+    let mut quotient = [0u64; 4];
+    let mut remainder = [0u64; 4];
+    for bit in 0..256 {
+        let limb = bit / 64;
+        let shift = 63 - bit % 64;
+        // shift the next bit of the numerator into the remainder
+        let carry = remainder[0] >> 63 == 1;
+        for i in 0..4 {
+            remainder[i] <<= 1;
+            if i < 3 {
+                remainder[i] |= remainder[i + 1] >> 63;
+            }
+        }
+        remainder[3] |= (numerator[limb] >> shift) & 1;
+        if carry || remainder >= denominator {
+            remainder = sub_limbs(remainder, denominator);
+            quotient[limb] |= 1 << shift;
+        }
+    }
+    quotient
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_hash() -> H256 {
     let mut rng = rand::thread_rng();
@@ -116,4 +233,28 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::H256;
+
+    #[test]
+    fn scale() {
+        let target: H256 = hex!("00ff000000000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(target.scale(1, 2), hex!("007f800000000000000000000000000000000000000000000000000000000000").into());
+        assert_eq!(target.scale(2, 1), hex!("01fe000000000000000000000000000000000000000000000000000000000000").into());
+        assert_eq!(target.scale(512, 1), H256::from([255u8; 32]));
+    }
+
+    #[test]
+    fn work() {
+        let easiest = H256::from([255u8; 32]);
+        assert_eq!(easiest.work(), hex!("0000000000000000000000000000000000000000000000000000000000000001").into());
+        // a target with the 8 leading bits at zero takes 2^8 hashes on average
+        let target: H256 = hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(target.work(), hex!("0000000000000000000000000000000000000000000000000000000000000100").into());
+        // the work of the zero target does not fit in 256 bits
+        assert_eq!(H256::from([0u8; 32]).work(), easiest);
+    }
+}
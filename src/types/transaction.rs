@@ -7,6 +7,7 @@ use bincode::{serialize, deserialize};
 use ring::digest;
 use super::hash::Hashable;
 use crate::types::address::Address;
+use crate::types::utxo::UtxoTransaction;
 use super::hash::H256;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -15,11 +16,36 @@ pub struct Transaction {
     pub receiver: Address,
     pub value: u128, // Not sure if u128 is necessary, but I assume it has to be very large (lot of Satoshis in one bitcoin)
     pub nonce: u128,
+    /// paid by the sender on top of the value, and collected by the miner of the block
+    pub fee: u128,
+}
+
+/// The first transaction of a block, paying the block reward and the collected fees to the miner.
+/// The height makes the coinbases of different blocks distinct.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Coinbase {
+    pub receiver: Address,
+    pub value: u128,
+    pub height: u128,
+}
+
+/// The signed content of a transaction, in one of the two ledger models, or an unsigned coinbase.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Payload {
+    Account(Transaction),
+    Utxo(UtxoTransaction),
+    Coinbase(Coinbase),
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Account(Transaction::default())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
-    pub transaction: Transaction,
+    pub transaction: Payload,
     pub signature: Vec<u8>,
     pub pubkey: Vec<u8>,
     // signature and pubkey represented as Vec<u8> for convenience --> check these structs as a part
@@ -32,8 +58,44 @@ impl Hashable for SignedTransaction {
     }    
 }
 
+impl SignedTransaction {
+    /// Sign a transaction payload with the given key
+    pub fn from_payload(transaction: Payload, key: &Ed25519KeyPair) -> Self {
+        let signature = sign(&transaction, key);
+        SignedTransaction {
+            transaction,
+            signature: signature.as_ref().to_vec(),
+            pubkey: key.public_key().as_ref().to_vec(),
+        }
+    }
+
+    /// Wrap a coinbase, which carries no signature
+    pub fn coinbase(coinbase: Coinbase) -> Self {
+        SignedTransaction {
+            transaction: Payload::Coinbase(coinbase),
+            signature: Vec::new(),
+            pubkey: Vec::new(),
+        }
+    }
+
+    /// Get the size of the transaction in a block, in bytes
+    pub fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+
+    /// Check whether the transaction is a coinbase
+    pub fn is_coinbase(&self) -> bool {
+        matches!(self.transaction, Payload::Coinbase(_))
+    }
+
+    /// Get the address of the key that signed the transaction
+    pub fn signer(&self) -> Address {
+        Address::from_public_key_bytes(&self.pubkey)
+    }
+}
+
 /// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+pub fn sign<T: Serialize>(t: &T, key: &Ed25519KeyPair) -> Signature {
     // convert Transaction to Vec<u8>
     let encoded: Vec<u8> = serialize(t).unwrap();
     // convert encoded to &[u8]
@@ -44,7 +106,7 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
+pub fn verify<T: Serialize>(t: &T, public_key: &[u8], signature: &[u8]) -> bool {
     // convert Transaction to Vec<u8>
     let encoded: Vec<u8>  = serialize(t).unwrap();
     // convert encoded to &[u8]
@@ -70,7 +132,8 @@ pub fn generate_random_transaction() -> Transaction {
     let receiver: Address = Address::from(ar2);
     let nonce: u128 =  thread_rng().gen();
     let value: u128 = thread_rng().gen();
-    let transaction = Transaction{sender, receiver, value, nonce};
+    let fee: u128 = thread_rng().gen();
+    let transaction = Transaction{sender, receiver, value, nonce, fee};
     transaction
 
 }
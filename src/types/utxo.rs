@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+use super::hash::H256;
+use crate::types::address::Address;
+
+/// Reference to an output of a previous transaction, by transaction hash and output index.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Input {
+    pub prev_tx: H256,
+    pub index: u32,
+}
+
+/// Value paid to an address, spendable by a later transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub receiver: Address,
+    pub value: u128,
+}
+
+/// A UTXO-model transaction: spends the outputs referenced by its inputs and creates new outputs.
+/// All inputs must be owned by the signer of the transaction.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+}
+
+impl std::fmt::Display for Input {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.prev_tx, self.index)
+    }
+}